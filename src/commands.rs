@@ -1,3 +1,4 @@
+pub mod apply;
 pub mod firmware;
 pub mod map;
 pub mod new;
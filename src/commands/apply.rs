@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use tokio::fs;
+use tracing::{info, instrument};
+
+use crate::{
+    commands::upload::{RemoteState, Summary, execute, plan::Plan, sd_card},
+    flipper::pick_cli,
+    storage::Storage,
+    types::remote_sync_file::SYNC_FILE_PATH,
+};
+
+#[instrument]
+pub async fn run(plan: PathBuf, target: Option<PathBuf>) -> Result<()> {
+    let plan: Plan = serde_json::from_slice(
+        &fs::read(&plan)
+            .await
+            .with_context(|| format!("failed to read plan at {}", plan.display()))?,
+    )?;
+
+    match target {
+        Some(mount) => apply(plan, sd_card(mount).await?).await,
+        None => apply(plan, pick_cli()?).await,
+    }
+}
+
+/// Executes exactly what is in the plan, no questions asked. It was reviewed already.
+async fn apply(plan: Plan, mut cli: impl Storage) -> Result<()> {
+    let state = RemoteState::read(&mut cli)?;
+    let operations = plan.operations(&state)?;
+    let sync_file = plan.sync_file()?;
+
+    let summary = Summary::of(&operations);
+
+    if summary.count() != 0 {
+        info!(
+            "Applying {} operation(s) (cp {}, mkdir {}, rm {}, mv {})",
+            summary.count(),
+            summary.copy,
+            summary.dir,
+            summary.remove,
+            summary.rename
+        );
+
+        execute(&mut cli, &operations, summary.count()).await?;
+    } else {
+        info!("All good, no operations to do.");
+    }
+
+    cli.fs_write(SYNC_FILE_PATH, sync_file, None)?;
+    state.write_managed(&mut cli, &plan.managed_files())?;
+
+    Ok(())
+}
@@ -1,13 +1,24 @@
+mod list;
+
+pub use list::{changelog, list};
+
 use std::{
     io::{Cursor, Read},
     path::{Path, PathBuf},
     sync::mpsc::channel,
+    time::SystemTime,
 };
 
 use crate::{
-    flipper::pick_cli,
+    flipper::{hardware_target, pick_cli},
     progress::progress,
-    types::{directory::File, firmware::Firmware, flip::Flip},
+    types::{
+        directory::{File, Target},
+        firmware::{Firmware, Package},
+        flip::Flip,
+        references::References,
+        releases,
+    },
 };
 use anyhow::bail;
 use cliclack::confirm;
@@ -16,11 +27,7 @@ use flipper_rpc::{
     fs::{FsCreateDir, FsRemove, FsWrite, helpers::os_str_to_str},
     proto::system::{UpdateRequest, reboot_request::RebootMode},
     rpc::req::Request,
-    transport::Transport,
-};
-use serde::{
-    Deserialize,
-    de::{self, IntoDeserializer, value::StringDeserializer},
+    transport::{Transport, serial::list_flipper_ports},
 };
 use tar::Archive;
 use tracing::{info, instrument, warn};
@@ -28,9 +35,8 @@ use uuid::Uuid;
 
 #[instrument]
 pub async fn set(mut flip: Flip, firmware: String) -> anyhow::Result<()> {
-    let deserializer: StringDeserializer<de::value::Error> = firmware.into_deserializer();
-
-    flip.firmware = Firmware::deserialize(deserializer)?;
+    flip.firmware = firmware.parse()?;
+    flip.validate_firmware()?;
 
     flip.write().await?;
 
@@ -43,28 +49,73 @@ pub async fn set(mut flip: Flip, firmware: String) -> anyhow::Result<()> {
 /// - Put all of it's files inside of /ext/update/xxx
 /// - Run Update on /ext/update/xxx/update.fuf
 /// - Reboot into update mode
+///
+/// The package is chosen for the hardware target of the connected flipper. With `target` no
+/// flipper is needed, the package then only lands in the store when none is connected.
 #[instrument]
-pub async fn update(flip: Flip) -> anyhow::Result<()> {
-    let firmware = flip.firmware;
-
-    let (url, sha265) = match firmware {
-        Firmware::Custom(url) => (url.parse()?, None),
-        _ => {
-            let version = firmware.fetch_manifest().await?;
-            let firmware_file = version.latest_tgz()?;
-
-            println!("{version}");
-            println!("{firmware_file}");
-
-            (
-                firmware_file.url.clone(),
-                Some(firmware_file.sha256.clone()),
-            )
+pub async fn update(flip: Flip, target: Option<Target>) -> anyhow::Result<()> {
+    let firmware = &flip.firmware;
+
+    let store = flip.store_path()?;
+
+    let (cli, target) = match target {
+        Some(target) => (None, target),
+        None => {
+            let mut cli = pick_cli()?;
+            let target = hardware_target(&mut cli)?;
+
+            info!("The flipper is an {target}");
+
+            (Some(cli), target)
         }
     };
 
+    let (url, sha265, version, package_target) = match firmware {
+        Firmware::Custom(url) => (url.parse()?, None, None, None),
+        _ => match firmware.fetch_manifest(&flip.firmware_sources).await {
+            Ok(mut version) => {
+                // Only needed to verify the download
+                releases::fetch_checksums(&mut version).await?;
+
+                let firmware_file = version.update_tgz(target)?;
+
+                println!("{version}");
+                println!("{firmware_file}");
+
+                (
+                    firmware_file.url.clone(),
+                    firmware_file.sha256.clone(),
+                    Some(version.version.clone()),
+                    Some(firmware_file.target),
+                )
+            }
+            // No network, e.g. a store restored by `store import`
+            Err(e) => {
+                let Some(package) = cached_package(&store, firmware, target).await? else {
+                    return Err(e.context("no package of this firmware in the store either"));
+                };
+
+                warn!(
+                    "Could not fetch the firmware directory ({e:#}), using version {} from the store",
+                    package.version.as_deref().unwrap_or("unknown")
+                );
+
+                (package.url, package.sha256, package.version, package.target)
+            }
+        },
+    };
+
     let store_id = Uuid::new_v5(&Uuid::NAMESPACE_URL, url.as_str().as_bytes());
-    let store_path = flip.source_path.join("store").join(store_id.to_string());
+    let store_path = store.join(store_id.to_string());
+
+    if flip.shared_store {
+        let mut references = References::load(&store).await?;
+        references
+            .project(&flip.source_path)
+            .firmware
+            .insert(store_id);
+        references.save(&store).await?;
+    }
 
     let tgz_name = url
         .path_segments()
@@ -79,8 +130,19 @@ pub async fn update(flip: Flip) -> anyhow::Result<()> {
             bail!("Aborted");
         }
 
-        tokio::fs::create_dir(&store_path).await?;
+        tokio::fs::create_dir_all(&store_path).await?;
         File::download(&url, sha265.as_deref(), &tgz_path).await?;
+
+        Package {
+            firmware: firmware.clone(),
+            url: url.clone(),
+            version,
+            target: package_target,
+            file: tgz_name.to_string(),
+            sha256: sha265,
+        }
+        .save(&store_path)
+        .await?;
     }
 
     let mut tar_gz = std::fs::File::open(&tgz_path)?;
@@ -94,7 +156,25 @@ pub async fn update(flip: Flip) -> anyhow::Result<()> {
     let tar = GzDecoder::new(reader);
     let mut archive = Archive::new(tar);
 
-    let mut cli = pick_cli()?;
+    let mut cli = match cli {
+        Some(cli) => cli,
+        None if list_flipper_ports()?.is_empty() => {
+            info!(
+                "No flipper is connected, {} is ready in the store",
+                tgz_path.display()
+            );
+            return Ok(());
+        }
+        None => pick_cli()?,
+    };
+
+    let device = hardware_target(&mut cli)?;
+
+    if let Some(package_target) = package_target
+        && !package_target.runs_on(device)
+    {
+        bail!("the package is built for {package_target}, refusing to install it on an {device}");
+    }
 
     cli.fs_create_dir("/ext/update")?;
     let mut base = None;
@@ -160,3 +240,35 @@ pub async fn update(flip: Flip) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Newest package of `firmware`, or its pinned version, for `target` that `firmware update`
+/// downloaded before
+async fn cached_package(
+    store: &Path,
+    firmware: &Firmware,
+    target: Target,
+) -> anyhow::Result<Option<Package>> {
+    if !tokio::fs::try_exists(store).await? {
+        return Ok(None);
+    }
+
+    let mut newest: Option<(SystemTime, Package)> = None;
+    let mut entries = tokio::fs::read_dir(store).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let Some(package) = Package::load(&entry.path()).await.ok().flatten() else {
+            continue;
+        };
+
+        let modified = entry.metadata().await?.modified()?;
+
+        let matches =
+            package.is_for(firmware) && package.target.is_none_or(|built| built.runs_on(target));
+
+        if matches && newest.as_ref().is_none_or(|(time, _)| modified > *time) {
+            newest = Some((modified, package));
+        }
+    }
+
+    Ok(newest.map(|(_, package)| package))
+}
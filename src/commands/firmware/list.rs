@@ -0,0 +1,224 @@
+//! `flippy firmware list` and `flippy firmware changelog`, browsing what the `directory.json` of
+//! every published firmware and every source of the project offers
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use tracing::{instrument, warn};
+use url::Url;
+
+use crate::types::{
+    directory::{Directory, Id, Target, Version},
+    firmware::{Firmware, FirmwareSource, SOURCES},
+};
+
+#[derive(Serialize, Debug)]
+struct SourceListing {
+    source: String,
+
+    /// `directory.json` or releases API
+    url: Url,
+    channels: Vec<ChannelListing>,
+}
+
+#[derive(Serialize, Debug)]
+struct ChannelListing {
+    id: Id,
+    title: String,
+    description: String,
+
+    /// Newest first
+    versions: Vec<VersionListing>,
+}
+
+#[derive(Serialize, Debug)]
+struct VersionListing {
+    version: String,
+    released: jiff::Timestamp,
+
+    /// File types available for every target
+    files: BTreeMap<Target, Vec<String>>,
+}
+
+#[derive(Serialize, Debug)]
+struct Changelog {
+    firmware: Firmware,
+    version: String,
+    released: jiff::Timestamp,
+    changelog: String,
+}
+
+/// Every published firmware and every source of `sources` when `source` is None, unreachable ones
+/// are skipped
+#[instrument]
+pub async fn list(
+    sources: HashMap<String, FirmwareSource>,
+    source: Option<String>,
+    json: bool,
+) -> Result<()> {
+    let mut named: Vec<(String, FirmwareSource)> = sources.into_iter().collect();
+    named.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut all: Vec<(String, FirmwareSource)> = SOURCES
+        .iter()
+        .map(|(name, directory)| Ok((name.to_string(), FirmwareSource::from_directory(directory)?)))
+        .collect::<Result<_>>()?;
+    all.extend(named);
+
+    let all = match source.as_deref() {
+        Some(source) => match all.iter().find(|(name, _)| name == source) {
+            Some(found) => vec![found.clone()],
+            None => bail!(
+                "unknown firmware `{source}`, expected {}",
+                all.iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        },
+        None => all,
+    };
+
+    let mut listings = vec![];
+
+    for (name, firmware_source) in all {
+        let fetched = firmware_source
+            .fetch()
+            .await
+            .with_context(|| format!("failed to fetch the `{name}` firmware directory"));
+
+        let fetched = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) if source.is_none() => {
+                warn!("{e:#}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        listings.push(listing(name, firmware_source.url()?.clone(), fetched)?);
+    }
+
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&listings)?),
+        false => print(&listings),
+    }
+
+    Ok(())
+}
+
+fn listing(source: String, url: Url, fetched: Directory) -> Result<SourceListing> {
+    let mut channels = vec![];
+
+    for channel in fetched.channels {
+        let mut versions = channel.versions.unwrap_or_default();
+        versions.sort_by_key(|version| std::cmp::Reverse(version.timestamp));
+
+        let versions = versions
+            .into_iter()
+            .map(|version| {
+                let mut files: BTreeMap<Target, Vec<String>> = BTreeMap::new();
+
+                for file in &version.files {
+                    files
+                        .entry(file.target)
+                        .or_default()
+                        .push(file.file_type.clone());
+                }
+
+                Ok(VersionListing {
+                    released: released(&version)?,
+                    version: version.version,
+                    files,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        channels.push(ChannelListing {
+            id: channel.id,
+            title: channel.title,
+            description: channel.description,
+            versions,
+        });
+    }
+
+    Ok(SourceListing {
+        source,
+        url,
+        channels,
+    })
+}
+
+fn print(listings: &[SourceListing]) {
+    for (i, listing) in listings.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+
+        println!("{} ({})", listing.source, listing.url);
+
+        for channel in &listing.channels {
+            println!();
+            println!("  {}@{}: {}", listing.source, channel.id, channel.title);
+
+            if !channel.description.is_empty() {
+                println!("  {}", channel.description);
+            }
+
+            if channel.versions.is_empty() {
+                println!("    no versions");
+            }
+
+            for version in &channel.versions {
+                println!(
+                    "    {:<24} released {}",
+                    version.version,
+                    version.released.strftime("%Y-%m-%d")
+                );
+
+                for (target, types) in &version.files {
+                    println!("      {target:<4} {}", types.join(", "));
+                }
+            }
+        }
+    }
+}
+
+/// Prints the full changelog of the version `firmware` resolves to
+#[instrument]
+pub async fn changelog(
+    sources: HashMap<String, FirmwareSource>,
+    firmware: Firmware,
+    json: bool,
+) -> Result<()> {
+    if let Firmware::Custom(url) = &firmware {
+        bail!("{url} is a custom firmware, it has no changelog");
+    }
+
+    let version = firmware.fetch_manifest(&sources).await?;
+
+    let changelog = Changelog {
+        firmware: firmware.unpinned(),
+        released: released(&version)?,
+        version: version.version,
+        changelog: version.changelog,
+    };
+
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&changelog)?),
+        false => {
+            println!("Version: {}", changelog.version);
+            println!("Released on: {}", changelog.released);
+            println!();
+            println!("{}", changelog.changelog.trim_end());
+        }
+    }
+
+    Ok(())
+}
+
+fn released(version: &Version) -> Result<jiff::Timestamp> {
+    jiff::Timestamp::from_second(version.timestamp as i64)
+        .with_context(|| format!("invalid release time of version {}", version.version))
+}
@@ -55,6 +55,7 @@ fn add_include(mapping: &mut Option<MappingEntry>, path: PathBuf) {
             *mapping = Some(MappingEntry {
                 include: vec![path],
                 exclude: vec![],
+                namespace: None,
             })
         }
     }
@@ -72,6 +73,7 @@ fn add_exclude(mapping: &mut Option<MappingEntry>, path: PathBuf) {
             *mapping = Some(MappingEntry {
                 include: vec![],
                 exclude: vec![path],
+                namespace: None,
             })
         }
     }
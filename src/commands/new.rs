@@ -113,9 +113,9 @@ fn new_wizard(default_name: Option<String>) -> anyhow::Result<(String, Firmware)
                 .interact()?;
 
             match firmware_type {
-                "official" => Firmware::Official(channel),
-                "unleashed" => Firmware::Unleashed(channel),
-                "momentum" => Firmware::Momentum(channel),
+                "official" => Firmware::Official(channel, None),
+                "unleashed" => Firmware::Unleashed(channel, None),
+                "momentum" => Firmware::Momentum(channel, None),
 
                 _ => unreachable!("todo: add more fw matches"),
             }
@@ -1,25 +1,70 @@
-use anyhow::anyhow;
+use anyhow::{Context, anyhow, bail};
+use gix::{ObjectId, open};
+use tokio::fs;
 use tracing::{debug, info, instrument};
 use url::Url;
 use uuid::Uuid;
 
 use crate::{
-    types::{flip::Flip, mapping::Mappings, repository::Repository},
-    validators::validate_project_name,
+    types::{
+        flip::Flip,
+        repository::{Pin, Repository, Source},
+    },
+    validators::{validate_namespace, validate_project_name},
 };
 
 #[instrument]
-pub async fn add(mut flip: Flip, url: Url, name: String) -> anyhow::Result<()> {
+pub async fn add(
+    mut flip: Flip,
+    url: String,
+    name: String,
+    namespace: Option<String>,
+    sha256: Option<String>,
+) -> anyhow::Result<()> {
+    let local = flip.source_path.join(&url);
+
     debug!("validating url");
-    let url = gix::url::parse(url.as_str().into())?;
+    let mut repository = if sha256.is_some() {
+        let archive =
+            Url::parse(&url).with_context(|| format!("`{url}` is not a valid archive URL"))?;
+
+        Repository {
+            archive: Some(archive),
+            sha256,
+            ..Default::default()
+        }
+    } else if local.is_dir() && open(&local).is_err() {
+        Repository {
+            path: Some(url.clone().into()),
+            ..Default::default()
+        }
+    } else {
+        let url = gix::url::parse(url.as_str().into())
+            .with_context(|| format!("`{url}` is not a valid git URL"))?;
+
+        Repository {
+            url: Some(url.to_string()),
+            ..Default::default()
+        }
+    };
+
+    repository.validate_source()?;
 
     debug!("Validating name");
     validate_project_name(&name)?;
 
-    let url = url.to_string();
-    let data = url.as_bytes();
+    if let Some(namespace) = &namespace {
+        debug!("Validating namespace");
+        validate_namespace(namespace)?;
+    }
+
+    let data = match repository.source()? {
+        Source::Git(url) => url.to_string(),
+        Source::Local(path) => path.display().to_string(),
+        Source::Archive { url, .. } => url.to_string(),
+    };
     debug!("inserting repository into list, building UUID");
-    let uuid = Uuid::new_v5(&Uuid::NAMESPACE_URL, data);
+    let uuid = Uuid::new_v5(&Uuid::NAMESPACE_URL, data.as_bytes());
 
     debug!("Checking if repo already exists");
     if flip
@@ -33,14 +78,9 @@ pub async fn add(mut flip: Flip, url: Url, name: String) -> anyhow::Result<()> {
     }
 
     info!(name, "Successfully created repo {}", name);
-    flip.repositories.insert(
-        name,
-        Repository {
-            uuid,
-            url,
-            mappings: Mappings::default(),
-        },
-    );
+    repository.uuid = uuid;
+    repository.namespace = namespace;
+    flip.repositories.insert(name, repository);
 
     flip.write().await?;
 
@@ -62,3 +102,111 @@ pub async fn remove(mut flip: Flip, name: String) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Kind of reference given to `repo pin`, detected from the store clone when not given
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum PinKind {
+    Branch,
+    Tag,
+    Rev,
+}
+
+#[instrument]
+pub async fn pin(
+    mut flip: Flip,
+    name: String,
+    reference: String,
+    kind: Option<PinKind>,
+) -> anyhow::Result<()> {
+    let store = flip.store_path()?;
+    let repo = flip
+        .repositories
+        .get_mut(&name)
+        .ok_or_else(|| anyhow!("repository: {} not found", name))?;
+
+    if !matches!(repo.source()?, Source::Git(_)) {
+        bail!("repository `{name}` is not a git repository, only those can be pinned");
+    }
+
+    let path = store.join(repo.uuid.to_string());
+    let clone = if fs::try_exists(&path).await? {
+        Some(open(&path)?)
+    } else {
+        None
+    };
+
+    let pin = match (kind, &clone) {
+        (Some(PinKind::Branch), _) => Pin::Branch(reference),
+        (Some(PinKind::Tag), _) => Pin::Tag(reference),
+        (Some(PinKind::Rev), clone) => match (ObjectId::from_hex(reference.as_bytes()), clone) {
+            // Resolved when checked out, the shallow clone is deepened until it is found
+            (Ok(id), _) => Pin::Rev(id.to_string()),
+            (Err(_), Some(clone)) => Pin::Rev(full_hash(clone, &reference)?),
+            // A short id may be outside of the clone, it could never be found by deepening
+            (Err(_), None) => bail!(
+                "repository `{name}` has not been fetched yet, `{reference}` must be a full 40 character commit id\n\t\tor run `flippy store fetch` first to pin a short one"
+            ),
+        },
+        (None, Some(clone)) => detect_pin(clone, reference)?,
+        (None, None) => bail!(
+            "repository `{name}` has not been fetched yet, pass `--kind` or run `flippy store fetch` first"
+        ),
+    };
+
+    info!(
+        "pinned {} to {}, run `flippy store fetch` to check it out",
+        name, pin
+    );
+    repo.set_pin(Some(pin));
+
+    flip.write().await?;
+
+    Ok(())
+}
+
+#[instrument]
+pub async fn unpin(mut flip: Flip, name: String) -> anyhow::Result<()> {
+    let repo = flip
+        .repositories
+        .get_mut(&name)
+        .ok_or_else(|| anyhow!("repository: {} not found", name))?;
+
+    match repo.pin()? {
+        Some(pin) => info!(
+            "unpinned {} from {}, it follows the default branch again",
+            name, pin
+        ),
+        None => bail!("repository: {} is not pinned", name),
+    }
+
+    repo.set_pin(None);
+
+    flip.write().await?;
+
+    Ok(())
+}
+
+fn detect_pin(clone: &gix::Repository, reference: String) -> anyhow::Result<Pin> {
+    let exists = |name: String| -> anyhow::Result<bool> {
+        Ok(clone.try_find_reference(name.as_str())?.is_some())
+    };
+
+    Ok(if exists(format!("refs/tags/{reference}"))? {
+        Pin::Tag(reference)
+    } else if exists(format!("refs/remotes/origin/{reference}"))? {
+        Pin::Branch(reference)
+    } else {
+        Pin::Rev(full_hash(clone, &reference)?)
+    })
+}
+
+/// Always store the full hash, an abbreviated one may become ambiguous later
+fn full_hash(clone: &gix::Repository, rev: &str) -> anyhow::Result<String> {
+    Ok(clone
+        .rev_parse_single(rev)
+        .with_context(|| format!("`{rev}` is not a branch, tag or commit in the store, pass `--kind` if it was not fetched yet"))?
+        .object()?
+        .peel_to_commit()?
+        .id
+        .to_string())
+}
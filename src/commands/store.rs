@@ -1,47 +1,296 @@
-use anyhow::{Result, bail};
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::IsTerminal,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
 use cliclack::confirm;
-use gix::open;
-use tokio::fs;
-use tracing::instrument;
+use gix::{bstr::BString, open};
+use prodash::{Progress, tree::Item};
+use tokio::{fs, sync::Semaphore, task::JoinSet};
+use tracing::{error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    git::{
+        self,
+        checkout::{Diverged, Head, checkout, checkout_head},
+    },
+    progress::progress,
+    source,
+    types::{
+        firmware::{Firmware, Package},
+        flip::Flip,
+        references::{REFERENCES_FILE, References},
+        repository::{Auth, Pin, Source},
+    },
+};
+
+mod bundle;
+mod status;
 
-use crate::{git, progress::progress, types::flip::Flip};
+pub use bundle::{export, import};
+pub use status::status;
+
+/// What `store fetch` did to one repository
+enum Fetched {
+    Cloned,
+    Updated(Option<Diverged>),
+    Extracted,
+    UpToDate,
+    Local,
+}
 
 #[instrument]
-pub async fn fetch(flip: Flip) -> Result<()> {
-    let path = flip.source_path;
+pub async fn fetch(flip: Flip, jobs: NonZeroUsize) -> Result<()> {
+    let store = flip.store_path()?;
+    fs::create_dir_all(&store).await?;
+
+    if flip.shared_store {
+        info!(store = %store.display(), "Using the shared store");
+
+        let mut references = References::load(&store).await?;
+        references.prune();
+        references.project(&flip.source_path).repositories = stored_repositories(&flip);
+        references.save(&store).await?;
+    }
+
+    // A broken `flip.toml` fails before anything is fetched
+    let repositories = flip
+        .repositories
+        .iter()
+        .map(|(name, repo)| Ok((name, repo, repo.local_path(&flip)?, repo.pin()?)))
+        .collect::<Result<Vec<_>>>()?;
 
     let (progress, handle) = progress();
+    let permits = Arc::new(Semaphore::new(jobs.get()));
+    let mut tasks = JoinSet::new();
+    let mut spawned = HashMap::new();
 
-    for (i, (name, repo)) in flip.repositories.iter().enumerate() {
-        let url = gix::url::parse(repo.url.as_str().into())?;
-        let path = path.join("store").join(repo.uuid.to_string());
-        let exists = fs::try_exists(&path).await?;
+    for (i, (name, repo, path, pin)) in repositories.into_iter().enumerate() {
+        let name = name.clone();
+        let url = repo.url.clone();
+        let archive = repo.archive.clone().zip(repo.sha256.clone());
+        let uuid = repo.uuid;
+        let auth = repo.auth.clone();
+        let sparse = flip.sparse_patterns(repo);
 
-        let sub_progress_str = format!(
-            "{}: {url} -> store/{}",
-            if exists { "fetching" } else { "cloning" },
-            repo.uuid
-        );
+        // Added up front so every repository keeps its place in the tree while it waits
+        let mut sub_progress = progress.add_child_with_id(name.clone(), (i as u32).to_le_bytes());
+        let permits = permits.clone();
+        let entry = (i, name.clone());
+
+        let task = tasks.spawn(async move {
+            let _permit = match permits.acquire_owned().await {
+                Ok(permit) => permit,
+                Err(e) => return (i, name, Err(e.into())),
+            };
+
+            let (sub_progress, result) = match (url, archive) {
+                (Some(url), _) => {
+                    match tokio::task::spawn_blocking(move || {
+                        let exists = path.exists();
+
+                        sub_progress.info(format!(
+                            "{}: {url} -> store/{uuid}",
+                            if exists { "fetching" } else { "cloning" },
+                        ));
+
+                        if let Some(pin) = &pin {
+                            sub_progress.info(format!("pinned to {pin}"));
+                        }
+
+                        let fetched = fetch_one(
+                            &url,
+                            &path,
+                            exists,
+                            pin.as_ref(),
+                            auth.as_ref(),
+                            sparse.as_deref(),
+                            &mut sub_progress,
+                        );
+
+                        (sub_progress, fetched.map(|fetched| (path, fetched)))
+                    })
+                    .await
+                    {
+                        Ok(fetched) => fetched,
+                        // Panicked, the progress went with it
+                        Err(e) => return (i, name, Err(e.into())),
+                    }
+                }
+                (None, Some((archive, sha256))) => {
+                    sub_progress.info(format!("downloading: {archive} -> store/{uuid}"));
+
+                    let fetched =
+                        source::fetch_archive(&archive, &sha256, &path, &mut sub_progress).await;
+
+                    let fetched = fetched.map(|extracted| match extracted {
+                        true => (path, Fetched::Extracted),
+                        false => (path, Fetched::UpToDate),
+                    });
+
+                    (sub_progress, fetched)
+                }
+                (None, None) => {
+                    let fetched = match path.is_dir() {
+                        true => Ok((path, Fetched::Local)),
+                        false => Err(anyhow!("folder {} does not exist", path.display())),
+                    };
 
-        let mut sub_progress = progress.add_child_with_id(name, (i as u32).to_le_bytes());
+                    (sub_progress, fetched)
+                }
+            };
 
-        sub_progress.info(sub_progress_str);
+            if let Err(e) = &result {
+                sub_progress.fail(format!("{e:#}"));
+            }
 
-        if exists {
-            let repo = open(path)?;
+            (i, name, result)
+        });
 
-            git::fetch::fetch(repo, None, &mut sub_progress)?;
-        } else {
-            git::clone::clone(url.to_string(), Some(path), &mut sub_progress)?;
+        spawned.insert(task.id(), entry);
+    }
+
+    let mut results = vec![];
+
+    while let Some(joined) = tasks.join_next_with_id().await {
+        match joined {
+            Ok((_, result)) => results.push(result),
+            // Only the repository of a task that panicked fails
+            Err(e) => {
+                let Some((i, name)) = spawned.remove(&e.id()) else {
+                    unreachable!("every task is recorded before joining")
+                };
+                results.push((i, name, Err(anyhow!("fetch task failed: {e}"))));
+            }
         }
     }
+    results.sort_by_key(|(i, ..)| *i);
 
     handle.shutdown_and_wait();
+
+    let mut failed = 0;
+    let mut diverged = vec![];
+
+    for (_, name, result) in results {
+        match result {
+            Ok((_, Fetched::Cloned)) => info!("`{name}`: cloned"),
+            Ok((_, Fetched::Extracted)) => info!("`{name}`: downloaded and extracted"),
+            Ok((_, Fetched::UpToDate)) => info!("`{name}`: archive already extracted"),
+            Ok((_, Fetched::Local)) => info!("`{name}`: local folder, nothing to fetch"),
+            Ok((path, Fetched::Updated(d))) => {
+                info!("`{name}`: fetched");
+
+                if let Some(d) = d {
+                    diverged.push((name, path, d));
+                }
+            }
+            Err(e) => {
+                error!("`{name}`: {e:#}");
+                failed += 1;
+            }
+        }
+    }
+
+    for (name, path, d) in diverged {
+        warn!(
+            "The history of `{name}` was rewritten upstream: {} is at {} but the remote is at {}, {} commit(s) in the store no longer exist upstream",
+            d.branch,
+            d.local.to_hex_with_len(7),
+            d.upstream.to_hex_with_len(7),
+            d.dropped
+        );
+
+        // Nobody can answer the prompt, e.g. in CI
+        if !std::io::stdin().is_terminal() {
+            warn!(
+                "Left `{name}` at {}, stdin is not a terminal. Run `flippy store fetch` in one to reset it",
+                d.local.to_hex_with_len(7)
+            );
+            continue;
+        }
+
+        if !confirm(format!(
+            "Reset `{name}` to the remote {}? Uploads keep using {} otherwise.",
+            d.branch,
+            d.local.to_hex_with_len(7)
+        ))
+        .interact()?
+        {
+            warn!("Left `{name}` at {}", d.local.to_hex_with_len(7));
+            continue;
+        }
+
+        checkout(
+            &open(path)?,
+            d.upstream,
+            Head::Branch(&d.branch),
+            gix::progress::Discard,
+        )?;
+
+        info!("Reset `{name}` to {}", d.upstream.to_hex_with_len(7));
+    }
+
+    if failed > 0 {
+        bail!(
+            "{failed} of {} repositories failed to fetch",
+            flip.repositories.len()
+        );
+    }
+
     Ok(())
 }
 
+/// Clones or fetches a single repository, blocking
+fn fetch_one(
+    url: &str,
+    path: &Path,
+    exists: bool,
+    pin: Option<&Pin>,
+    auth: Option<&Auth>,
+    sparse: Option<&[BString]>,
+    progress: &mut Item,
+) -> Result<Fetched> {
+    if !exists {
+        let url = gix::url::parse(url.into())?;
+        git::clone::clone(
+            url.to_string(),
+            Some(path),
+            pin,
+            auth,
+            sparse,
+            &mut *progress,
+        )?;
+
+        return Ok(Fetched::Cloned);
+    }
+
+    let repo = open(path)?;
+
+    // Written first, so a checkout done by the fetch already uses them
+    let remap = git::sparse::write(&repo, sparse)?;
+
+    let diverged = git::fetch::fetch(repo, None, pin, auth, &mut *progress)?;
+
+    if remap {
+        progress.info("mappings changed, checking out the new paths".to_string());
+        checkout_head(&open(path)?, &mut *progress)?;
+    }
+
+    Ok(Fetched::Updated(diverged))
+}
+
 #[instrument]
 pub async fn clean(flip: Flip) -> Result<()> {
+    if flip.shared_store {
+        return clean_shared(flip).await;
+    }
+
     let path = flip.source_path;
 
     if !confirm("Delete all store items? This includes repos, firmware, caches, ...etc.")
@@ -50,8 +299,391 @@ pub async fn clean(flip: Flip) -> Result<()> {
         bail!("Aborted");
     }
 
-    tokio::fs::remove_dir_all(path.join("store")).await?;
-    tokio::fs::create_dir(path.join("store")).await?;
+    fs::remove_dir_all(path.join("store")).await?;
+    fs::create_dir(path.join("store")).await?;
+
+    Ok(())
+}
+
+/// Deletes clones and archives of repositories no longer in `flip.toml` (in any project's, when
+/// shared), leftovers of interrupted downloads and all but the `keep` newest firmware packages no
+/// project references. The package the current firmware resolves to is always kept.
+#[instrument]
+pub async fn gc(flip: Flip, keep: usize, repack: bool) -> Result<()> {
+    let store = flip.store_path()?;
+
+    if !fs::try_exists(&store).await? {
+        info!("The store does not exist, nothing to collect");
+        return Ok(());
+    }
+
+    let mut references = None;
+    let used: BTreeSet<Uuid> = if flip.shared_store {
+        let mut shared = References::load(&store).await?;
+        shared.prune();
+        shared.project(&flip.source_path).repositories = stored_repositories(&flip);
+
+        let used = shared
+            .projects
+            .values()
+            .flat_map(|project| project.repositories.iter().chain(&project.firmware))
+            .copied()
+            .collect();
+
+        references = Some(shared);
+        used
+    } else {
+        stored_repositories(&flip)
+    };
+
+    let mut unused = vec![];
+    let mut firmware = vec![];
+    let mut clones = vec![];
+
+    let mut entries = fs::read_dir(&store).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let uuid = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| Uuid::parse_str(name).ok());
+
+        match uuid {
+            Some(uuid) if used.contains(&uuid) => {
+                if path.join(".git").exists() {
+                    clones.push(path);
+                }
+            }
+            Some(_) => match firmware_package(&path)? {
+                Some((_, modified)) => {
+                    let package = Package::load(&path).await.ok().flatten();
+                    firmware.push((modified, path, package));
+                }
+                None => unused.push(path),
+            },
+            None if entry.file_name() == REFERENCES_FILE => {}
+            // `.download` and `.extracting` of interrupted archive downloads
+            None => unused.push(path),
+        }
+    }
+
+    let (firmware, stale) = stale_firmware(firmware, &flip.firmware, keep);
+
+    let doomed: Vec<(PathBuf, u64)> = unused
+        .into_iter()
+        .chain(stale)
+        .map(|path| {
+            let size = disk_usage(&path);
+            (path, size)
+        })
+        .collect();
+
+    let mut reclaimed: u64 = doomed.iter().map(|(_, size)| size).sum();
+
+    if doomed.is_empty() {
+        info!(
+            "No unused store entries, kept {} firmware package(s)",
+            firmware.len()
+        );
+    } else {
+        for (path, size) in &doomed {
+            info!("unused: {} ({})", path.display(), human_size(*size));
+        }
+
+        if !confirm(format!(
+            "Delete {} unused store entries, {}?",
+            doomed.len(),
+            human_size(reclaimed)
+        ))
+        .interact()?
+        {
+            bail!("Aborted");
+        }
+
+        for (path, _) in &doomed {
+            match path.is_dir() {
+                true => fs::remove_dir_all(path).await?,
+                false => fs::remove_file(path).await?,
+            }
+        }
+    }
+
+    if let Some(references) = references {
+        references.save(&store).await?;
+    }
+
+    if repack {
+        for path in &clones {
+            let git_dir = path.join(".git");
+            let before = disk_usage(&git_dir);
+
+            let status = tokio::process::Command::new("git")
+                .arg("-C")
+                .arg(path)
+                .args(["repack", "-a", "-d", "-q"])
+                .status()
+                .await
+                .context("failed to run `git repack`, is git installed?")?;
+
+            if !status.success() {
+                bail!("`git repack` failed in {}", path.display());
+            }
+
+            reclaimed += before.saturating_sub(disk_usage(&git_dir));
+        }
+
+        info!("Repacked {} clone(s)", clones.len());
+    }
+
+    info!(
+        "Reclaimed {}, deleted {} store entries",
+        human_size(reclaimed),
+        doomed.len()
+    );
+
+    Ok(())
+}
+
+/// UUIDs of the repositories that live in the store, local folders do not
+fn stored_repositories(flip: &Flip) -> BTreeSet<Uuid> {
+    flip.repositories
+        .values()
+        .filter(|repo| !matches!(repo.source(), Ok(Source::Local(_))))
+        .map(|repo| repo.uuid)
+        .collect()
+}
+
+/// Splits firmware packages no project references into the ones to keep and the stale ones. The
+/// newest package of `current` for every target is what `firmware update` installs without
+/// network, it is kept on top of the `keep` newest.
+fn stale_firmware(
+    mut packages: Vec<(SystemTime, PathBuf, Option<Package>)>,
+    current: &Firmware,
+    keep: usize,
+) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    // Newest first
+    packages.sort_by_key(|(modified, ..)| std::cmp::Reverse(*modified));
+
+    let mut targets = vec![];
+    let (resolved, others): (Vec<_>, Vec<_>) = packages.into_iter().partition(|(.., package)| {
+        let Some(package) = package.as_ref().filter(|package| package.is_for(current)) else {
+            return false;
+        };
+
+        if targets.contains(&package.target) {
+            return false;
+        }
+
+        targets.push(package.target);
+        true
+    });
+
+    let mut kept = resolved
+        .into_iter()
+        .map(|(_, path, _)| path)
+        .collect::<Vec<_>>();
+    let mut stale = others
+        .into_iter()
+        .map(|(_, path, _)| path)
+        .collect::<Vec<_>>();
+
+    kept.extend(stale.drain(..keep.min(stale.len())));
+
+    (kept, stale)
+}
+
+/// The `.tgz` `firmware update` downloaded into `path` and when, None if it is not a package
+fn firmware_package(path: &Path) -> Result<Option<(PathBuf, SystemTime)>> {
+    if !path.is_dir() || path.join(".git").exists() || path.join(source::ARCHIVE_MARKER).exists() {
+        return Ok(None);
+    }
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+
+        if entry.path().extension().is_some_and(|ext| ext == "tgz") {
+            return Ok(Some((entry.path(), entry.metadata()?.modified()?)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Bytes taken by every file below `path`
+fn disk_usage(path: &Path) -> u64 {
+    walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Releases the references of this project, then deletes every entry no other project uses
+async fn clean_shared(flip: Flip) -> Result<()> {
+    let store = flip.store_path()?;
+
+    let mut references = References::load(&store).await?;
+    references.projects.remove(&flip.source_path);
+    references.prune();
+
+    let mut unused = vec![];
+    let mut kept = 0;
+
+    if fs::try_exists(&store).await? {
+        let mut entries = fs::read_dir(&store).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            // Skips references.json and leftovers of interrupted downloads
+            let Some(uuid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+
+            match references.is_referenced(&uuid) {
+                true => kept += 1,
+                false => unused.push(entry.path()),
+            }
+        }
+    }
+
+    if !unused.is_empty() {
+        if !confirm(format!(
+            "Delete {} shared store entries no project uses? {kept} entries used by other projects are kept.",
+            unused.len()
+        ))
+        .interact()?
+        {
+            bail!("Aborted");
+        }
+
+        for path in &unused {
+            fs::remove_dir_all(path).await?;
+        }
+    }
+
+    references.save(&store).await?;
+
+    info!(
+        store = %store.display(),
+        "Deleted {} shared store entries, kept {kept} used by other projects",
+        unused.len()
+    );
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::directory::Target;
+    use std::time::Duration;
+
+    fn package(
+        firmware: &str,
+        version: &str,
+        target: Target,
+        age: u64,
+    ) -> (SystemTime, PathBuf, Option<Package>) {
+        let path = PathBuf::from(format!("{firmware}-{version}-{target}"));
+        let package = Package {
+            firmware: firmware.parse().unwrap(),
+            url: format!("https://example.com/{version}.tgz")
+                .parse()
+                .unwrap(),
+            version: Some(version.to_string()),
+            target: Some(target),
+            file: format!("{version}.tgz"),
+            sha256: None,
+        };
+
+        (
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1000 - age),
+            path,
+            Some(package),
+        )
+    }
+
+    #[test]
+    fn keeps_the_current_firmware() {
+        let packages = vec![
+            package("official@release", "1.0.0", Target::F7, 3),
+            package("momentum@release", "2.0.0", Target::F7, 1),
+            package("momentum@release", "2.1.0", Target::F7, 0),
+        ];
+
+        let (kept, stale) = stale_firmware(packages, &"official@release".parse().unwrap(), 1);
+
+        assert_eq!(
+            kept,
+            [
+                PathBuf::from("official@release-1.0.0-f7"),
+                PathBuf::from("momentum@release-2.1.0-f7"),
+            ]
+        );
+        assert_eq!(stale, [PathBuf::from("momentum@release-2.0.0-f7")]);
+    }
+
+    #[test]
+    fn keeps_a_pinned_version_for_every_target() {
+        let packages = vec![
+            package("official@release", "1.0.0", Target::F7, 4),
+            package("official@release", "1.0.0", Target::F18, 3),
+            package("official@release", "1.1.0", Target::F7, 0),
+        ];
+
+        let (kept, stale) = stale_firmware(packages, &"official@release:1.0.0".parse().unwrap(), 0);
+
+        assert_eq!(
+            kept,
+            [
+                PathBuf::from("official@release-1.0.0-f18"),
+                PathBuf::from("official@release-1.0.0-f7"),
+            ]
+        );
+        assert_eq!(stale, [PathBuf::from("official@release-1.1.0-f7")]);
+    }
+
+    #[test]
+    fn keep_applies_to_other_packages() {
+        let packages = vec![
+            package("official@release", "1.0.0", Target::F7, 2),
+            package("official@release", "1.1.0", Target::F7, 1),
+            package("official@release", "1.2.0", Target::F7, 0),
+        ];
+
+        let (kept, stale) = stale_firmware(packages, &"official@release".parse().unwrap(), 1);
+
+        // The newest is what the unpinned firmware resolves to, `keep` adds the next one
+        assert_eq!(
+            kept,
+            [
+                PathBuf::from("official@release-1.2.0-f7"),
+                PathBuf::from("official@release-1.1.0-f7"),
+            ]
+        );
+        assert_eq!(stale, [PathBuf::from("official@release-1.0.0-f7")]);
+    }
+}
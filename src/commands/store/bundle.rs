@@ -0,0 +1,421 @@
+//! `flippy store export` and `flippy store import`, moving the store to machines without network
+//! access.
+//!
+//! The tar holds `manifest.json`, a git bundle of every clone together with its refs and config
+//! (`repositories/<uuid>/`), every extracted archive (`archives/<uuid>/`) and every firmware
+//! package (`firmware/<uuid>/`). Bundles are made with `git bundle`, importing them needs no git.
+//!
+//! Clones are bundled as shallow as they are in the store. Without network, `upload` uses
+//! `walking_diff` for a repository whose commit from the sync file is older than the bundle.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::AtomicBool,
+};
+
+use anyhow::{Context, Result, bail};
+use gix::{odb::pack, open};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use super::firmware_package;
+use crate::{
+    git::{self, checkout::checkout_head},
+    types::{
+        firmware::{PACKAGE_FILE, Package},
+        flip::Flip,
+        references::References,
+        repository::{Repository, Source},
+    },
+};
+
+const VERSION: u8 = 1;
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Files of `.git` that are copied next to the bundle, the bundle itself only carries objects.
+/// Symbolic refs like `refs/remotes/origin/HEAD` survive this way.
+const GIT_FILES: [&str; 5] = ["HEAD", "config", "shallow", "packed-refs", "flippy_fetched"];
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    version: u8,
+    created: jiff::Timestamp,
+    repositories: Vec<Entry>,
+    archives: Vec<Entry>,
+    firmware: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    uuid: Uuid,
+
+    /// Repository name in the exporting project, the firmware version for firmware
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+
+    /// Repository or archive URL, the `.tgz` for firmware
+    source: String,
+}
+
+#[instrument]
+pub async fn export(flip: Flip, bundle: PathBuf) -> Result<()> {
+    let store = flip.store_path()?;
+
+    let firmware = match flip.shared_store {
+        true => Some(
+            References::load(&store)
+                .await?
+                .project(&flip.source_path)
+                .firmware
+                .clone(),
+        ),
+        false => None,
+    };
+
+    let summary =
+        tokio::task::block_in_place(|| write_bundle(&flip, &store, firmware.as_ref(), &bundle))?;
+
+    info!(
+        "Exported {} repositories, {} archives and {} firmware packages",
+        summary.repositories.len(),
+        summary.archives.len(),
+        summary.firmware.len()
+    );
+
+    Ok(())
+}
+
+/// `firmware` limits the firmware packages to these, every package in the store otherwise
+fn write_bundle(
+    flip: &Flip,
+    store: &Path,
+    firmware: Option<&std::collections::BTreeSet<Uuid>>,
+    bundle: &Path,
+) -> Result<Manifest> {
+    let staging = tempdir(&std::env::temp_dir(), "export")?;
+    let result = (|| {
+        let mut tar = tar::Builder::new(File::create(bundle)?);
+        let mut manifest = Manifest {
+            version: VERSION,
+            created: jiff::Timestamp::now(),
+            repositories: vec![],
+            archives: vec![],
+            firmware: vec![],
+        };
+
+        let mut repositories: Vec<(&String, &Repository)> = flip.repositories.iter().collect();
+        repositories.sort_by_key(|(name, _)| *name);
+
+        for (name, repo) in repositories {
+            let path = repo.local_path(flip)?;
+            let uuid = repo.uuid;
+
+            let entry = |source: &str| Entry {
+                uuid,
+                name: Some(name.clone()),
+                source: source.to_string(),
+            };
+
+            match repo.source()? {
+                Source::Local(_) => {
+                    warn!("`{name}` is a local folder, it is not part of the bundle");
+                }
+                _ if !path.exists() => {
+                    warn!(
+                        "`{name}` was not fetched yet, run `flippy store fetch` first to include it"
+                    );
+                }
+                Source::Git(url) => {
+                    let file = staging.join(format!("{uuid}.bundle"));
+
+                    let status = Command::new("git")
+                        .arg("-C")
+                        .arg(&path)
+                        .args(["bundle", "create", "-q"])
+                        .arg(&file)
+                        .arg("--all")
+                        .status()
+                        .context("failed to run `git bundle`, is git installed?")?;
+
+                    if !status.success() {
+                        bail!("`git bundle` failed for `{name}`");
+                    }
+
+                    let prefix = Path::new("repositories").join(uuid.to_string());
+                    tar.append_path_with_name(&file, prefix.join("repo.bundle"))?;
+
+                    let git_dir = path.join(".git");
+                    for file in GIT_FILES {
+                        if git_dir.join(file).exists() {
+                            tar.append_path_with_name(git_dir.join(file), prefix.join(file))?;
+                        }
+                    }
+                    tar.append_dir_all(prefix.join("refs"), git_dir.join("refs"))?;
+
+                    manifest.repositories.push(entry(url));
+                    info!("`{name}`: bundled");
+                }
+                Source::Archive { url, .. } => {
+                    tar.append_dir_all(Path::new("archives").join(uuid.to_string()), &path)?;
+
+                    manifest.archives.push(entry(url.as_str()));
+                    info!("`{name}`: archived");
+                }
+            }
+        }
+
+        if store.exists() {
+            for dir in std::fs::read_dir(store)? {
+                let dir = dir?.path();
+
+                let Some(uuid) = dir
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| Uuid::parse_str(name).ok())
+                else {
+                    continue;
+                };
+
+                if firmware.is_some_and(|firmware| !firmware.contains(&uuid)) {
+                    continue;
+                }
+
+                let Some((file, _)) = firmware_package(&dir)? else {
+                    continue;
+                };
+
+                tar.append_dir_all(Path::new("firmware").join(uuid.to_string()), &dir)?;
+
+                let package = std::fs::read(dir.join(PACKAGE_FILE))
+                    .ok()
+                    .and_then(|data| serde_json::from_slice::<Package>(&data).ok());
+
+                manifest.firmware.push(Entry {
+                    uuid,
+                    name: package.and_then(|package| package.version),
+                    source: file
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                });
+            }
+        }
+
+        let data = serde_json::to_vec_pretty(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created.as_second() as u64);
+        tar.append_data(&mut header, MANIFEST_FILE, data.as_slice())?;
+
+        tar.into_inner()?.sync_all()?;
+
+        Ok(manifest)
+    })();
+
+    std::fs::remove_dir_all(&staging)?;
+
+    result
+}
+
+#[instrument]
+pub async fn import(flip: Flip, bundle: PathBuf) -> Result<()> {
+    let store = flip.store_path()?;
+    fs::create_dir_all(&store).await?;
+
+    let (manifest, imported) = tokio::task::block_in_place(|| read_bundle(&flip, &store, &bundle))?;
+
+    if flip.shared_store {
+        let mut references = References::load(&store).await?;
+        let project = references.project(&flip.source_path);
+
+        project.repositories.extend(
+            flip.repositories
+                .values()
+                .filter(|repo| imported.contains(&repo.uuid))
+                .map(|repo| repo.uuid),
+        );
+        project
+            .firmware
+            .extend(manifest.firmware.iter().map(|entry| entry.uuid));
+
+        references.save(&store).await?;
+    }
+
+    info!(
+        "Imported {} of {} store entries, made {}",
+        imported.len(),
+        manifest.repositories.len() + manifest.archives.len() + manifest.firmware.len(),
+        manifest.created
+    );
+
+    Ok(())
+}
+
+/// Returns the manifest and the UUIDs that were restored, entries already in the store are kept
+fn read_bundle(flip: &Flip, store: &Path, bundle: &Path) -> Result<(Manifest, Vec<Uuid>)> {
+    let staging = tempdir(store, "import")?;
+
+    let result = (|| {
+        tar::Archive::new(File::open(bundle)?)
+            .unpack(&staging)
+            .with_context(|| format!("failed to unpack {}", bundle.display()))?;
+
+        let manifest: Manifest = serde_json::from_slice(
+            &std::fs::read(staging.join(MANIFEST_FILE))
+                .context("the bundle has no manifest, was it made by `flippy store export`?")?,
+        )?;
+
+        if manifest.version != VERSION {
+            bail!(
+                "unsupported bundle version {}, expected {VERSION}",
+                manifest.version
+            );
+        }
+
+        let mut imported = vec![];
+
+        let sections = [
+            ("repositories", &manifest.repositories),
+            ("archives", &manifest.archives),
+            ("firmware", &manifest.firmware),
+        ];
+
+        for (section, entries) in sections {
+            for entry in entries {
+                let label = entry.name.as_deref().unwrap_or(&entry.source);
+                let dest = store.join(entry.uuid.to_string());
+
+                if dest.exists() {
+                    info!("`{label}`: already in the store, keeping it");
+                    continue;
+                }
+
+                let from = staging.join(section).join(entry.uuid.to_string());
+
+                match section {
+                    "repositories" => {
+                        let repo = flip.repositories.values().find(|r| r.uuid == entry.uuid);
+
+                        // Restored next to the store and moved in when complete, a failed restore
+                        // must not look like a clone on the next import
+                        let restored = staging.join("restored").join(entry.uuid.to_string());
+
+                        restore_clone(flip, repo, &from, &restored)
+                            .with_context(|| format!("failed to restore `{label}`"))?;
+                        std::fs::rename(&restored, &dest)?;
+                    }
+                    _ => std::fs::rename(&from, &dest)?,
+                }
+
+                info!("`{label}`: restored");
+                imported.push(entry.uuid);
+            }
+        }
+
+        Ok((manifest, imported))
+    })();
+
+    std::fs::remove_dir_all(&staging)?;
+
+    result
+}
+
+/// Initializes a clone at `dest` from the bundle and git files in `from`, then checks it out with
+/// the sparse patterns of `repo`
+fn restore_clone(flip: &Flip, repo: Option<&Repository>, from: &Path, dest: &Path) -> Result<()> {
+    let clone = gix::init(dest)?;
+
+    let mut reader = BufReader::new(File::open(from.join("repo.bundle"))?);
+    let mut line = String::new();
+
+    reader.read_line(&mut line)?;
+    if line.trim_end() != "# v2 git bundle" {
+        bail!("not a v2 git bundle");
+    }
+
+    // Refs come from the copied files, prerequisites would mean history is missing
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+
+        match line.trim_end() {
+            "" => break,
+            prerequisite if prerequisite.starts_with('-') => {
+                bail!("the bundle depends on commits it does not contain")
+            }
+            _ => {}
+        }
+    }
+
+    let pack_dir = clone.objects.store_ref().path().join("pack");
+    std::fs::create_dir_all(&pack_dir)?;
+
+    let outcome = pack::Bundle::write_to_directory(
+        &mut reader,
+        Some(&pack_dir),
+        &mut gix::progress::Discard,
+        &AtomicBool::new(false),
+        None::<gix::objs::find::Never>,
+        pack::bundle::write::Options {
+            object_hash: clone.object_hash(),
+            ..Default::default()
+        },
+    )?;
+
+    let git_dir = clone.git_dir().to_path_buf();
+
+    for file in GIT_FILES {
+        if from.join(file).exists() {
+            std::fs::copy(from.join(file), git_dir.join(file))?;
+        }
+    }
+    copy_dir(&from.join("refs"), &git_dir.join("refs"))?;
+
+    // Refs point into the pack now, it no longer needs protecting
+    if let Some(keep) = outcome.keep_path {
+        std::fs::remove_file(keep)?;
+    }
+
+    let clone = open(dest)?;
+
+    if let Some(repo) = repo {
+        git::sparse::write(&clone, flip.sparse_patterns(repo).as_deref())?;
+    }
+
+    checkout_head(&clone, gix::progress::Discard)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry?;
+        let dest = to.join(entry.path().strip_prefix(from)?);
+
+        match entry.file_type().is_dir() {
+            true => std::fs::create_dir_all(&dest)?,
+            false => {
+                std::fs::copy(entry.path(), &dest)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Fresh directory below `parent`, removed by the caller
+fn tempdir(parent: &Path, purpose: &str) -> Result<PathBuf> {
+    let dir = parent.join(format!(".flippy-{purpose}-{}", std::process::id()));
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::create_dir_all(&dir)?;
+
+    Ok(dir)
+}
@@ -0,0 +1,319 @@
+//! `flippy store status`, what the store holds for every repository and firmware package
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use gix::open;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tracing::instrument;
+use uuid::Uuid;
+
+use super::{disk_usage, firmware_package, human_size};
+use crate::{
+    git, source,
+    types::{
+        firmware::{Firmware, Package},
+        flip::Flip,
+        repository::{Pin, Repository, Source},
+    },
+};
+
+#[derive(Serialize, Debug)]
+struct Status {
+    store: PathBuf,
+    repositories: Vec<RepositoryStatus>,
+    firmware: Vec<FirmwareStatus>,
+}
+
+#[derive(Serialize, Debug)]
+struct RepositoryStatus {
+    name: String,
+
+    /// `git`, `local` or `archive`
+    kind: &'static str,
+
+    /// URL, folder or archive URL
+    source: String,
+    uuid: Uuid,
+    path: PathBuf,
+
+    /// False until `store fetch` cloned or extracted it
+    present: bool,
+    commit: Option<String>,
+
+    /// Commits on the remote branch, as of the last fetch, that are not checked out. None when
+    /// pinned to a tag or commit.
+    behind: Option<usize>,
+    fetched: Option<jiff::Timestamp>,
+
+    /// Bytes on disk, history included
+    size: u64,
+    mappings: Vec<MappingStatus>,
+}
+
+#[derive(Serialize, Debug)]
+struct MappingStatus {
+    name: &'static str,
+
+    /// Files of the checkout the mapping includes
+    files: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct FirmwareStatus {
+    uuid: Uuid,
+    file: PathBuf,
+
+    /// None for packages downloaded before this was recorded
+    firmware: Option<Firmware>,
+    version: Option<String>,
+    downloaded: jiff::Timestamp,
+    size: u64,
+    sha256: Check,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum Check {
+    Ok,
+    Mismatch,
+
+    /// Custom firmware, or downloaded before checksums were recorded
+    Unknown,
+}
+
+#[instrument]
+pub async fn status(flip: Flip, json: bool) -> Result<()> {
+    let store = flip.store_path()?;
+
+    let mut repositories = vec![];
+
+    for (name, repo) in &flip.repositories {
+        repositories.push(repository(&flip, name, repo)?);
+    }
+
+    repositories.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut firmware = vec![];
+
+    if fs::try_exists(&store).await? {
+        let mut entries = fs::read_dir(&store).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+
+            let Some(uuid) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+
+            if let Some((file, modified)) = firmware_package(&path)? {
+                firmware.push(package(uuid, &path, file, modified).await?);
+            }
+        }
+    }
+
+    // Newest first
+    firmware.sort_by_key(|package| std::cmp::Reverse(package.downloaded));
+
+    let status = Status {
+        store,
+        repositories,
+        firmware,
+    };
+
+    match json {
+        true => println!("{}", serde_json::to_string_pretty(&status)?),
+        false => print(&status),
+    }
+
+    Ok(())
+}
+
+fn repository(flip: &Flip, name: &str, repo: &Repository) -> Result<RepositoryStatus> {
+    let path = repo.local_path(flip)?;
+    let present = path.exists();
+
+    let (kind, source) = match repo.source()? {
+        Source::Git(url) => ("git", url.to_string()),
+        Source::Local(folder) => ("local", folder.display().to_string()),
+        Source::Archive { url, .. } => ("archive", url.to_string()),
+    };
+
+    let mut status = RepositoryStatus {
+        name: name.to_string(),
+        kind,
+        source,
+        uuid: repo.uuid,
+        path: path.clone(),
+        present,
+        commit: None,
+        behind: None,
+        fetched: None,
+        size: 0,
+        mappings: vec![],
+    };
+
+    if !present {
+        return Ok(status);
+    }
+
+    match repo.source()? {
+        Source::Git(_) => {
+            let clone = open(&path)?;
+
+            status.commit = Some(clone.head_id()?.to_string());
+            status.behind = behind(&clone, repo.pin()?.as_ref())?;
+            status.fetched = git::fetch::last_time(&clone)?;
+        }
+        Source::Archive { .. } => {
+            let extracted = std::fs::metadata(path.join(source::ARCHIVE_MARKER))?.modified()?;
+            status.fetched = Some(extracted.try_into()?);
+        }
+        Source::Local(_) => {}
+    }
+
+    status.size = disk_usage(&path);
+
+    // Exactly what upload would see, a sparse clone only holds what its mappings include
+    let files = source::files(&path)?;
+
+    for mapping in repo.mappings.iter() {
+        let (_, matched) = source::matching(&files, mapping.entry())?;
+
+        status.mappings.push(MappingStatus {
+            name: mapping.name(),
+            files: matched.len(),
+        });
+    }
+
+    Ok(status)
+}
+
+/// Commits the remote branch is ahead of HEAD, counted from what the last fetch received
+fn behind(clone: &gix::Repository, pin: Option<&Pin>) -> Result<Option<usize>> {
+    let branch = match pin {
+        Some(Pin::Branch(branch)) => branch.clone(),
+        // Tags and commits never move
+        Some(Pin::Tag(_) | Pin::Rev(_)) => return Ok(None),
+        None => match clone.head_name()? {
+            Some(name) => name.shorten().to_string(),
+            None => return Ok(None),
+        },
+    };
+
+    let Some(mut upstream) =
+        clone.try_find_reference(format!("refs/remotes/origin/{branch}").as_str())?
+    else {
+        return Ok(None);
+    };
+
+    let upstream = upstream.peel_to_id_in_place()?.detach();
+    let head = clone.head_id()?.detach();
+
+    let mut behind = 0;
+    for commit in clone.rev_walk([upstream]).with_hidden([head]).all()? {
+        commit?;
+        behind += 1;
+    }
+
+    Ok(Some(behind))
+}
+
+async fn package(
+    uuid: Uuid,
+    dir: &Path,
+    file: PathBuf,
+    modified: SystemTime,
+) -> Result<FirmwareStatus> {
+    let package = Package::load(dir).await?;
+    let data = fs::read(&file).await?;
+
+    let sha256 = match package.as_ref().and_then(|p| p.sha256.as_deref()) {
+        Some(expected) => match hex::encode(Sha256::digest(&data)).eq_ignore_ascii_case(expected) {
+            true => Check::Ok,
+            false => Check::Mismatch,
+        },
+        None => Check::Unknown,
+    };
+
+    let (firmware, version) = match package {
+        Some(package) => (Some(package.firmware), package.version),
+        None => (None, None),
+    };
+
+    Ok(FirmwareStatus {
+        uuid,
+        file,
+        firmware,
+        version,
+        downloaded: modified.try_into()?,
+        size: data.len() as u64,
+        sha256,
+    })
+}
+
+fn print(status: &Status) {
+    println!("Store: {}", status.store.display());
+
+    for repo in &status.repositories {
+        println!();
+        println!("{} ({}) {}", repo.name, repo.kind, repo.uuid);
+        println!("  source   {}", repo.source);
+
+        if !repo.present {
+            println!("  not fetched yet, run `flippy store fetch`");
+            continue;
+        }
+
+        if let Some(commit) = &repo.commit {
+            match repo.behind {
+                Some(behind) => println!("  commit   {commit}, {behind} behind"),
+                None => println!("  commit   {commit}"),
+            }
+        }
+
+        if let Some(fetched) = &repo.fetched {
+            println!("  fetched  {fetched}");
+        }
+
+        println!("  size     {}", human_size(repo.size));
+
+        for mapping in &repo.mappings {
+            println!("  {:<8} {} file(s)", mapping.name, mapping.files);
+        }
+    }
+
+    println!();
+
+    if status.firmware.is_empty() {
+        println!("No firmware packages");
+        return;
+    }
+
+    println!("Firmware packages");
+
+    for package in &status.firmware {
+        let version = package.version.as_deref().unwrap_or("unknown version");
+        let check = match package.sha256 {
+            Check::Ok => "sha256 ok",
+            Check::Mismatch => "sha256 MISMATCH",
+            Check::Unknown => "sha256 not recorded",
+        };
+
+        println!(
+            "  {} {version}, {}, downloaded {}, {check}",
+            package.uuid,
+            human_size(package.size),
+            package.downloaded
+        );
+    }
+}
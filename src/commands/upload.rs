@@ -1,40 +1,140 @@
 use crate::flipper::pick_cli;
 use crate::progress::progress;
+use crate::storage::{SdCard, Storage};
 use crate::{
-    commands::upload::diff::diff_all_repositories, types::remote_sync_file::SYNC_FILE_PATH,
+    commands::upload::{diff::diff_all_repositories, plan::Plan},
+    types::remote_sync_file::SYNC_FILE_PATH,
 };
 use crate::{
-    types::{flip::Flip, remote_sync_file::SyncFile},
-    walking_diff::diff::Op,
+    types::{
+        flip::Flip,
+        managed_files::{MANAGED_FILE_PATH, ManagedFiles},
+        remote_sync_file::SyncFile,
+    },
+    walking_diff::diff::{Op, Reason},
 };
 use anyhow::{Result, bail};
 use cliclack::confirm;
-use flipper_rpc::fs::{FsCreateDir, FsRead, FsRemove, FsWrite};
 use gix::{Commit, open};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::channel;
 use tokio::fs;
-use tracing::{info, instrument, warn};
+use tracing::{debug, info, instrument, warn};
 
 mod diff;
 mod pathspec;
+pub mod plan;
 
 #[instrument]
-pub async fn run(flip: Flip, force_walkdir: bool) -> Result<()> {
-    if force_walkdir {
-        unimplemented!(
-            "--force-walkdir is not fully implemented yet. Please delete {SYNC_FILE_PATH} manually, then rerun this command."
-        );
+pub async fn run(
+    flip: Flip,
+    force_walkdir: bool,
+    target: Option<PathBuf>,
+    plan: Option<PathBuf>,
+) -> Result<()> {
+    match target {
+        Some(mount) => upload(flip, force_walkdir, plan, sd_card(mount).await?).await,
+        None => upload(flip, force_walkdir, plan, pick_cli()?).await,
     }
+}
+
+pub async fn sd_card(mount: PathBuf) -> Result<SdCard> {
+    if !fs::try_exists(&mount).await? {
+        bail!("SD card mount point {} does not exist", mount.display());
+    }
+
+    info!(mount = %mount.display(), "Writing directly to a mounted SD card");
+    Ok(SdCard::new(mount))
+}
 
-    let mut cli = pick_cli()?;
+/// Raw sync file and managed files list on the remote, None when they do not exist
+pub struct RemoteState {
+    pub sync_file: Option<Vec<u8>>,
+    pub managed: Option<Vec<u8>>,
+}
 
-    // TODO: Implement SD card writing. Option to take out the SD card and write to it directly (if the host has a SD reader), instead of sending files through RPC. this will improve speed greatly for those who can
+impl RemoteState {
+    pub fn read(cli: &mut impl Storage) -> Result<Self> {
+        fn read_optional(cli: &mut impl Storage, path: &str) -> Result<Option<Vec<u8>>> {
+            match cli.fs_read(path) {
+                Ok(data) => Ok(Some(data.into_owned())),
+                Err(flipper_rpc::error::Error::Rpc(
+                    flipper_rpc::rpc::error::Error::StorageError(
+                        flipper_rpc::rpc::error::StorageError::NotFound,
+                    ),
+                )) => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        }
 
-    let sync_file = cli
-        .fs_read(SYNC_FILE_PATH)
-        .map_err(Into::into)
-        .and_then(SyncFile::deserialize);
+        Ok(Self {
+            sync_file: read_optional(cli, SYNC_FILE_PATH)?,
+            managed: read_optional(cli, MANAGED_FILE_PATH)?,
+        })
+    }
+
+    /// Writes the managed files list, unless the remote already holds exactly that. It is as
+    /// large as the databases, rewriting it on every upload would wear the SD card.
+    pub fn write_managed(&self, cli: &mut impl Storage, managed: &ManagedFiles) -> Result<()> {
+        let data = managed.serialize();
+
+        if self.managed.as_deref() != Some(data.as_slice()) {
+            cli.fs_write(MANAGED_FILE_PATH, data, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Identifies this exact state, used to check a plan is applied onto what it was made for
+    pub fn digest(&self) -> String {
+        let mut ctx = md5::Context::new();
+
+        for data in [&self.sync_file, &self.managed] {
+            match data {
+                Some(data) => {
+                    ctx.consume([1]);
+                    ctx.consume((data.len() as u64).to_le_bytes());
+                    ctx.consume(data);
+                }
+                None => ctx.consume([0]),
+            }
+        }
+
+        hex::encode(*ctx.finalize())
+    }
+}
+
+async fn upload(
+    flip: Flip,
+    force_walkdir: bool,
+    plan: Option<PathBuf>,
+    mut cli: impl Storage,
+) -> Result<()> {
+    let state = RemoteState::read(&mut cli)?;
+
+    let sync_file = if force_walkdir {
+        // Pretend the sync file is blank, every repository will then go through walking_diff and
+        // the sync file will be rebuilt from the result.
+        info!("--force-walkdir passed, ignoring the sync file and re-verifying every mapping");
+        Some(SyncFile::default())
+    } else {
+        state
+            .sync_file
+            .as_ref()
+            .map(SyncFile::deserialize)
+            .transpose()?
+    };
+
+    // Ownership is kept even with --force-walkdir, it is the only thing that keeps user files safe
+    let mut managed = match &state.managed {
+        Some(data) => ManagedFiles::deserialize(data)?,
+        None => {
+            info!(
+                "No managed files list at '{MANAGED_FILE_PATH}', files not uploaded by this version of flippy will be treated as unmanaged"
+            );
+            ManagedFiles::default()
+        }
+    };
 
     let mut operations: Vec<Op> = vec![];
     let mut updated_sync_file = SyncFile {
@@ -42,7 +142,7 @@ pub async fn run(flip: Flip, force_walkdir: bool) -> Result<()> {
     };
 
     match sync_file {
-        Ok(sync_file) => {
+        Some(sync_file) => {
             // Yes! The syncfile exists already. We must check if each REPO exists inside of it,
             // but we have *some* data.
             //
@@ -53,19 +153,11 @@ pub async fn run(flip: Flip, force_walkdir: bool) -> Result<()> {
                 &mut operations,
                 sync_file,
                 &mut updated_sync_file,
+                &mut managed,
             )
             .await?;
         }
-        Err(e)
-            if matches!(
-                e.downcast_ref::<flipper_rpc::error::Error>(),
-                Some(flipper_rpc::error::Error::Rpc(
-                    flipper_rpc::rpc::error::Error::StorageError(
-                        flipper_rpc::rpc::error::StorageError::NotFound
-                    )
-                ))
-            ) =>
-        {
+        None => {
             // Oh no! The flipper has not been initialized. We must manually copy using
             // a tree diff FOR EVERY REPOSITORY since the .flippy_do_not_remove syncfile
             // could have been deleted. If  it was deleted the upload will take a fuckton
@@ -81,30 +173,41 @@ pub async fn run(flip: Flip, force_walkdir: bool) -> Result<()> {
 
             info!("Creating blank sync file, rerun command for walking-diff");
         }
-        Err(e) => return Err(e),
     }
 
-    let (mut copy, mut dir, mut remove) = (0usize, 0usize, 0usize);
+    let summary = Summary::of(&operations);
 
-    for op in &operations {
-        match op {
-            Op::Copy(..) => copy += 1,
-            Op::CreateDir(..) => dir += 1,
-            Op::Remove(..) => remove += 1,
-            _ => (),
-        }
-    }
+    if let Some(path) = plan {
+        let plan = Plan::new(&flip, &operations, &state, &updated_sync_file, &managed)?;
 
-    let count = copy + dir + remove;
+        fs::write(&path, serde_json::to_vec_pretty(&plan)?).await?;
 
-    if count == 0 {
+        info!(
+            path = %path.display(),
+            "Wrote plan with {} operation(s) (cp {}, mkdir {}, rm {}, mv {}), run `flippy apply` to execute it",
+            summary.count(),
+            summary.copy,
+            summary.dir,
+            summary.remove,
+            summary.rename
+        );
+        return Ok(());
+    }
+
+    if summary.count() == 0 {
         info!("All good, no operations to do.");
         cli.fs_write(SYNC_FILE_PATH, updated_sync_file.serialize(), None)?;
+        state.write_managed(&mut cli, &managed)?;
         return Ok(());
     }
 
     let confirm = confirm(format!(
-        "Perform {count} operation(s)? (cp {copy}, mkdir {dir}, rm {remove})",
+        "Perform {} operation(s)? (cp {}, mkdir {}, rm {}, mv {})",
+        summary.count(),
+        summary.copy,
+        summary.dir,
+        summary.remove,
+        summary.rename
     ))
     .interact()?;
 
@@ -112,6 +215,67 @@ pub async fn run(flip: Flip, force_walkdir: bool) -> Result<()> {
         bail!("Aborted");
     }
 
+    execute(&mut cli, &operations, summary.count()).await?;
+
+    // If you select NO on the confirm, running the command again will make itself think that it
+    // ran the last time, desyncing the commit hash
+    // Only update if it didnt fail (likely in beta)
+    cli.fs_write(SYNC_FILE_PATH, updated_sync_file.serialize(), None)?;
+    state.write_managed(&mut cli, &managed)?;
+
+    Ok(())
+}
+
+/// Operation counts, warns about every unmanaged file while counting
+pub struct Summary {
+    pub copy: usize,
+    pub dir: usize,
+    pub remove: usize,
+    pub rename: usize,
+}
+
+impl Summary {
+    pub fn of(operations: &[Op]) -> Self {
+        let (mut copy, mut dir, mut remove, mut rename) = (0usize, 0usize, 0usize, 0usize);
+        let mut unmanaged = vec![];
+        let mut remote_root = "";
+
+        for op in operations {
+            match op {
+                Op::Mapping(_, remote) => remote_root = remote,
+                Op::Copy(..) => copy += 1,
+                Op::CreateDir(..) => dir += 1,
+                Op::Remove(..) => remove += 1,
+                Op::Rename(..) => rename += 1,
+                Op::Unmanaged(path_buf) => unmanaged.push(Path::new(remote_root).join(path_buf)),
+                _ => (),
+            }
+        }
+
+        if !unmanaged.is_empty() {
+            warn!(
+                "{} unmanaged file(s) are not part of any repository and will be left alone:",
+                unmanaged.len()
+            );
+            for path in &unmanaged {
+                warn!("unmanaged: {}", path.display());
+            }
+        }
+
+        Self {
+            copy,
+            dir,
+            remove,
+            rename,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.copy + self.dir + self.remove + self.rename
+    }
+}
+
+pub async fn execute(cli: &mut impl Storage, operations: &[Op], count: usize) -> Result<()> {
     info!("Doing those aforementioned operations");
 
     let mut repo = &PathBuf::new();
@@ -124,7 +288,7 @@ pub async fn run(flip: Flip, force_walkdir: bool) -> Result<()> {
 
     item.init(Some(count), None);
 
-    for op in &operations {
+    for op in operations {
         match op {
             Op::Repo(path_buf) => {
                 repo = path_buf;
@@ -132,8 +296,14 @@ pub async fn run(flip: Flip, force_walkdir: bool) -> Result<()> {
             Op::Mapping(local, remote) => {
                 mapping_root_local = PathBuf::from(local);
                 mapping_root_remote = PathBuf::from(remote);
+
+                // The database folder (or namespace) may not exist yet on a fresh SD card
+                if let Some(parent) = mapping_root_remote.parent() {
+                    cli.fs_create_dir(parent)?;
+                }
+                cli.fs_create_dir(&mapping_root_remote)?;
             }
-            Op::Copy(path_buf) => {
+            Op::Copy(path_buf, _) => {
                 let from = repo.join(&mapping_root_local).join(path_buf);
                 let to = mapping_root_remote.join(path_buf);
                 let child = item.add_child(format!("copy {from:?} -> {to:?}"));
@@ -154,18 +324,45 @@ pub async fn run(flip: Flip, force_walkdir: bool) -> Result<()> {
 
                 item.inc();
             }
-            Op::CreateDir(path_buf) => {
+            Op::CreateDir(path_buf, _) => {
                 let to = mapping_root_remote.join(path_buf);
 
                 cli.fs_create_dir(to)?;
                 item.inc();
             }
-            Op::Remove(path_buf) => {
+            Op::Remove(path_buf, reason) => {
                 let to = mapping_root_remote.join(path_buf);
-                cli.fs_remove(to, true)?;
+
+                match cli.fs_remove(&to, *reason != Reason::Emptied) {
+                    // Something the user placed is still inside
+                    Err(flipper_rpc::error::Error::Rpc(
+                        flipper_rpc::rpc::error::Error::StorageError(
+                            flipper_rpc::rpc::error::StorageError::DirectoryNotEmpty,
+                        ),
+                    )) if *reason == Reason::Emptied => {
+                        info!("Keeping {}, it still holds unmanaged files", to.display());
+                    }
+                    // Already gone, e.g. an earlier upload failed halfway
+                    Err(flipper_rpc::error::Error::Rpc(
+                        flipper_rpc::rpc::error::Error::StorageError(
+                            flipper_rpc::rpc::error::StorageError::NotFound,
+                        ),
+                    )) => {
+                        debug!("{} was already removed", to.display());
+                    }
+                    result => result?,
+                }
 
                 item.inc();
             }
+            Op::Rename(from, to, _) => {
+                let from = mapping_root_remote.join(from);
+                let to = mapping_root_remote.join(to);
+
+                cli.fs_rename(from, to)?;
+                item.inc();
+            }
+            Op::Unmanaged(..) => {}
         };
     }
 
@@ -176,10 +373,5 @@ pub async fn run(flip: Flip, force_walkdir: bool) -> Result<()> {
 
     handle.shutdown_and_wait();
 
-    // If you select NO on the confirm, running the command again will make itself think that it
-    // ran the last time, desyncing the commit hash
-    // Only update if it didnt fail (likely in beta)
-    cli.fs_write(SYNC_FILE_PATH, updated_sync_file.serialize(), None)?;
-
     Ok(())
 }
@@ -1,122 +1,164 @@
 use crate::{
     Flip,
-    commands::upload::{Commit, Path, bail, info, open, pathspec::pathspec_from_pattern},
-    git::diff::diff_from_head,
+    commands::upload::{Commit, Path, bail, info, open, pathspec::pathspec_from_pattern, warn},
+    git::{self, checkout::checkout_head, diff::diff_from_head, shallow::Deepened},
+    source,
+    storage::Storage,
     types::{
-        mapping::MappingInfo,
+        managed_files::ManagedFiles,
+        mapping::{MappingEntry, MappingInfo},
         remote_sync_file::{Repo, SyncFile},
+        repository::{Repository, Source},
+    },
+    walking_diff::{
+        self,
+        diff::{Op, Reason},
     },
-    walking_diff::{self, diff::Op},
 };
 use anyhow::{Context, Result};
-use flipper_rpc::{
-    fs::{FsReadDir, helpers::os_str_to_str},
-    transport::serial::rpc::SerialRpcTransport,
-};
+use flipper_rpc::fs::{FsReadDir, helpers::os_str_to_str};
 use fxhash::{FxBuildHasher, FxHashMap};
-use gix::{Pathspec, bstr::ByteSlice};
-use std::ffi::OsString;
-use std::path::PathBuf;
+use gix::{
+    ObjectId, Pathspec,
+    bstr::{BStr, ByteSlice},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ffi::OsString,
+    path::PathBuf,
+};
 use tokio::fs;
 
 pub async fn diff_all_repositories(
     flip: &Flip,
-    cli: &mut SerialRpcTransport,
+    cli: &mut impl Storage,
     operations: &mut Vec<Op>,
     sync_file: SyncFile,
     updated_sync_file: &mut SyncFile,
+    managed: &mut ManagedFiles,
 ) -> Result<()> {
+    let destinations = all_destinations(flip);
+    // Everything the mappings upload this time, the rest of `managed` is left over
+    let mut uploaded = ManagedFiles::default();
+
     for (name, repo) in &flip.repositories {
-        let url = gix::url::parse(repo.url.as_str().into())?;
         let uuid = repo.uuid;
-        let path = flip.source_path.join("store").join(uuid.to_string());
+        let path = repo.local_path(flip)?;
 
         if !fs::try_exists(&path).await? {
-            bail!(
-                "repository `{name}` at `{url}` does not exist but is in store\n\t\tplease run `flippy store fetch` to download all repositories"
-            );
+            match repo.source()? {
+                Source::Local(local) => bail!(
+                    "folder `{}` of repository `{name}` does not exist",
+                    local.display()
+                ),
+                Source::Git(url) => bail!(
+                    "repository `{name}` at `{url}` does not exist but is in store\n\t\tplease run `flippy store fetch` to download all repositories"
+                ),
+                Source::Archive { url, .. } => bail!(
+                    "archive `{url}` of repository `{name}` was not extracted yet\n\t\tplease run `flippy store fetch` to download all repositories"
+                ),
+            }
         }
 
+        let namespace = repo.namespace.as_deref();
         let mappings = repo.mappings.iter();
-        let repo = open(&path)?;
+        let mut synced = sync_file.find(&uuid);
+
+        let (hash, content) = match repo.source()? {
+            Source::Git(_) => {
+                let (hash, remote_commit, repo) = git_state(flip, name, repo, &path, &mut synced)?;
+
+                (
+                    hash,
+                    Content::Git {
+                        repo: Box::new(repo),
+                        remote_commit,
+                    },
+                )
+            }
+            Source::Local(_) | Source::Archive { .. } => {
+                let files = source::files(&path)?;
+                let hash = source::manifest(&path, &files)?;
 
-        let head_hash = repo.head_commit()?.id;
-        let head_hash = head_hash.as_slice();
+                match synced {
+                    Some(s) if s.hash == hash => {
+                        info!("`{name}` did not change since the last upload")
+                    }
+                    _ => info!("Using walking_diff for `{name}`, it is not a git repository"),
+                }
 
-        let remote_hash = sync_file.find_hash(&uuid);
+                (hash, Content::Files(files))
+            }
+        };
 
         operations.push(Op::Repo(path.clone()));
 
-        match remote_hash {
-            Some(remote_hash) => {
-                info!("Using `git diff` with the state from the sync file");
+        let mut digests = Vec::new();
 
-                let remote_commit = repo.find_commit(*remote_hash)?;
+        for mapping in mappings {
+            let info = mapping.info(namespace);
+            let digest = info.digest();
 
-                for mapping in mappings {
-                    let MappingInfo {
-                        patterns: p,
-                        destination,
-                        ignore: _,
-                    } = mapping.info();
+            let MappingInfo {
+                patterns: p,
+                destination,
+                ignore,
+            } = info;
 
-                    let (mut spec, _) = pathspec_from_pattern(&repo, p.patterns())?;
+            let (lcd_path, paths) = match &content {
+                Content::Git { repo, .. } => git_paths(repo, p)?,
+                Content::Files(files) => source::select(files, p)?,
+            };
 
-                    let search = spec.search();
+            let lcd = os_str_to_str(lcd_path.as_os_str())?.to_string();
 
-                    let lcd_path = search
-                        .longest_common_directory()
-                        .context("longest_common_directory was None")?;
+            // Marker for this mapping
+            operations.push(Op::Mapping(lcd.clone(), destination.clone()));
+            let mapping_ops = operations.len();
 
-                    let lcd = os_str_to_str(lcd_path.as_os_str())?;
+            let local_root = path.join(&lcd_path);
 
-                    // Marker for this mapping
-                    operations.push(Op::Mapping(lcd.to_string(), destination));
+            match (synced, &content) {
+                (
+                    Some(synced),
+                    Content::Git {
+                        repo,
+                        remote_commit: Some(remote_commit),
+                    },
+                ) if synced.tracks(&digest) => {
+                    let (mut spec, _) = pathspec_from_pattern(repo, p.patterns())?;
 
                     // Now generate the git-based adds/removes under this mapping
-                    git_diff(remote_commit.clone(), operations, &mut spec)
-                        .context("failed to run git_diff for mapping")?;
+                    git_diff(
+                        repo.find_commit(*remote_commit)?,
+                        &lcd,
+                        &paths,
+                        operations,
+                        &mut spec,
+                    )
+                    .context("failed to run git_diff for mapping")?;
                 }
-            }
-            None => {
-                info!(
-                    "Using the slower walking_diff method, this will only happen once for an initial clone."
-                );
-
-                for mapping in mappings {
-                    let MappingInfo {
-                        patterns: p,
-                        destination,
-                        ignore,
-                    } = mapping.info();
-
-                    let (
-                        //
-                        mut spec,
-                        local_state,
-                    ) = pathspec_from_pattern(&repo, p.patterns())?;
-
-                    let search = spec.search();
-
-                    // Remove the folder path from the repository, it will be readded when we operate on
-                    // it
-                    let lcd_path = search
-                        .longest_common_directory()
-                        .context("longest_common_directory was None")?;
-
-                    let lcd = os_str_to_str(lcd_path.as_os_str())?;
-
-                    operations.push(Op::Mapping(lcd.to_string(), destination));
-
-                    let local_root = path.join(&lcd_path);
-                    let removal_length = lcd.len() + 1;
+                // Exactly what was uploaded last time
+                (Some(synced), Content::Files(_))
+                    if synced.tracks(&digest) && synced.hash == hash => {}
+                _ => {
+                    match synced {
+                        Some(synced) if synced.mappings.is_none() => info!(
+                            destination,
+                            "The sync file did not record mappings yet, using walking_diff for it once"
+                        ),
+                        Some(synced) if !synced.tracks(&digest) => info!(
+                            destination,
+                            "Mapping changed since the last upload, using walking_diff for it"
+                        ),
+                        _ => {}
+                    }
 
-                    let paths = spec
-                        .index_entries_with_paths(&local_state).context("Index was empty, no files to change. You may ignore this if your pathspecs did not match anything")?.map(|(str, entry)| {
-                            let str = &str[removal_length..];
-                            let str = str.to_os_str().context("Path was not UTF-8").unwrap();
-                            (Path::new(str), entry.stat.size)
-                        })
+                    // Never walk into another mapping's namespace, it would look remote-only
+                    let ignore = ignore
+                        .iter()
+                        .copied()
+                        .chain(nested_namespaces(&destinations, &destination))
                         .collect::<Vec<_>>();
 
                     walking_diff(
@@ -124,62 +166,434 @@ pub async fn diff_all_repositories(
                         cli,
                         &paths,
                         &local_root,
-                        destination,
-                        ignore,
+                        &destination,
+                        &ignore,
+                        managed,
                         operations,
                     )?;
                 }
             }
+
+            // After the upload, the device holds exactly the local tree of this mapping
+            for op in &operations[mapping_ops..] {
+                if let Op::Remove(removed, _) | Op::Rename(removed, _, _) = op {
+                    managed.remove(Path::new(&destination).join(removed));
+                }
+            }
+            for (local, _) in &paths {
+                managed.insert(&destination, local);
+                uploaded.insert(&destination, local);
+            }
+
+            digests.push(digest);
         }
 
         updated_sync_file.repositories.push(Repo {
             uuid: *uuid.as_bytes(),
-            hash: head_hash.try_into()?,
+            hash,
+            mappings: Some(digests),
         });
     }
 
+    // Operations must belong to a repository for plans, with none there is nothing to attach
+    // them to
+    if !flip.repositories.is_empty() {
+        remove_orphans(managed, &uploaded, &destinations, operations);
+    }
+
     Ok(())
 }
 
-fn git_diff(remote_commit: Commit<'_>, ops: &mut Vec<Op>, search: &mut Pathspec) -> Result<()> {
-    ops.extend(
-        diff_from_head(remote_commit)?
+/// What a repository's mappings are listed from
+enum Content {
+    Git {
+        repo: Box<gix::Repository>,
+
+        /// Commit from the sync file, present in the store
+        remote_commit: Option<ObjectId>,
+    },
+    Files(Vec<(PathBuf, u32)>),
+}
+
+/// Checks the store clone is ready to upload from. Returns its HEAD, the commit from the sync
+/// file when a git diff from it is possible, and the clone. Resets `synced` when it is not.
+fn git_state(
+    flip: &Flip,
+    name: &str,
+    repo: &Repository,
+    path: &Path,
+    synced: &mut Option<&Repo>,
+) -> Result<([u8; 20], Option<ObjectId>, gix::Repository)> {
+    let sparse = flip.sparse_patterns(repo);
+    let pin = repo.pin()?;
+    let auth = repo.auth.as_ref();
+    let had_sync = synced.is_some();
+    let repo = open(path)?;
+
+    // Only what the mappings included at the last checkout is in the worktree. HEAD is complete
+    // in the clone, so the new paths are checked out without a fetch.
+    let repo = match git::sparse::write(&repo, sparse.as_deref())? {
+        true => {
+            info!("Mappings of `{name}` changed since the last fetch, checking out the new paths");
+            checkout_head(&repo, gix::progress::Discard)?;
+            open(path)?
+        }
+        false => repo,
+    };
+
+    // Files are read from the worktree, it must be exactly at the pinned commit
+    if let Some(pin) = &pin {
+        let pinned = git::pin::resolve(&repo, pin, auth)?;
+        let head = repo.head_id()?.detach();
+
+        if head != pinned {
+            bail!(
+                "repository `{name}` is pinned to {pin} ({}) but the store is at {}\n\t\tplease run `flippy store fetch` to check it out",
+                pinned.to_hex_with_len(7),
+                head.to_hex_with_len(7)
+            );
+        }
+    }
+
+    let head_hash = repo.head_commit()?.id;
+
+    let mut remote_commit = None;
+
+    if let Some(s) = *synced {
+        let id = gix::ObjectId::from(s.hash);
+
+        let deepened = match repo.has_object(id) {
+            true => Deepened::Found,
+            false => git::shallow::deepen_until(&repo, id, auth)?,
+        };
+
+        if let Deepened::Found = deepened {
+            remote_commit = Some(id);
+        } else if let Deepened::Unreachable(e) = deepened {
+            warn!(
+                "Could not download the history of `{name}` to find commit {} from the sync file ({e:#}), using walking_diff for it",
+                id.to_hex_with_len(7)
+            );
+            *synced = None;
+        } else if repo.is_shallow() {
+            warn!(
+                "Commit {} of `{name}` from the sync file is more than {} commits behind, using walking_diff for it instead of downloading more history",
+                id.to_hex_with_len(7),
+                git::shallow::MAX_DEPTH
+            );
+            *synced = None;
+        } else {
+            // Force-pushed upstream, or the store was re-cloned. Walking once rebuilds the
+            // state and the sync file gets the new HEAD.
+            warn!(
+                "Commit {} of `{name}` from the sync file no longer exists in the store, upstream history was likely rewritten. Using walking_diff for it instead",
+                id.to_hex_with_len(7)
+            );
+            *synced = None;
+        }
+    }
+
+    match synced {
+        Some(_) => info!("Using `git diff` with the state from the sync file"),
+        // Already warned about above
+        None if had_sync => {}
+        None => info!(
+            "Using the slower walking_diff method, this will only happen once for an initial clone."
+        ),
+    }
+
+    Ok((head_hash.as_slice().try_into()?, remote_commit, repo))
+}
+
+/// Longest common directory of a mapping's pathspecs and the index entries below it, relative to
+/// that directory
+fn git_paths(
+    repo: &gix::Repository,
+    patterns: &MappingEntry,
+) -> Result<(PathBuf, Vec<(PathBuf, u32)>)> {
+    let (
+        //
+        mut spec,
+        local_state,
+    ) = pathspec_from_pattern(repo, patterns.patterns())?;
+
+    let search = spec.search();
+
+    // Remove the folder path from the repository, it will be readded when we operate on
+    // it
+    let lcd_path = search
+        .longest_common_directory()
+        .context("longest_common_directory was None")?
+        .into_owned();
+
+    let lcd = os_str_to_str(lcd_path.as_os_str())?;
+    let removal_length = if lcd.is_empty() { 0 } else { lcd.len() + 1 };
+
+    let paths = spec
+        .index_entries_with_paths(&local_state).context("Index was empty, no files to change. You may ignore this if your pathspecs did not match anything")?.map(|(str, entry)| {
+            let str = &str[removal_length..];
+            let str = str.to_os_str().context("Path was not UTF-8").unwrap();
+            (Path::new(str).to_path_buf(), entry.stat.size)
+        })
+        .collect::<Vec<_>>();
+
+    Ok((lcd_path, paths))
+}
+
+/// Every remote destination of every mapping, warning about the ones shared between repositories
+fn all_destinations(flip: &Flip) -> Vec<String> {
+    let mut destinations: Vec<(&str, String)> = flip
+        .repositories
+        .iter()
+        .flat_map(|(name, repo)| {
+            repo.mappings.iter().map(|mapping| {
+                (
+                    name.as_str(),
+                    mapping.info(repo.namespace.as_deref()).destination,
+                )
+            })
+        })
+        .collect();
+
+    destinations.sort_by(|(_, a), (_, b)| a.cmp(b));
+
+    for pair in destinations.windows(2) {
+        let [(a, destination), (b, other)] = pair else {
+            unreachable!("windows of 2")
+        };
+
+        if destination == other {
+            warn!(
+                destination,
+                "repositories `{a}` and `{b}` both upload into the same folder and will remove each other's files, set a `namespace` on one of them"
+            );
+        }
+    }
+
+    destinations.dedup_by(|(_, a), (_, b)| a == b);
+    destinations
+        .into_iter()
+        .map(|(_, destination)| destination)
+        .collect()
+}
+
+/// Removes managed paths no mapping uploads anymore, left behind when a namespace changed or a
+/// mapping was removed. Nothing walks their old destination, so no diff would find them.
+fn remove_orphans(
+    managed: &mut ManagedFiles,
+    uploaded: &ManagedFiles,
+    destinations: &[String],
+    ops: &mut Vec<Op>,
+) {
+    let orphans = managed
+        .paths
+        .difference(&uploaded.paths)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if orphans.is_empty() {
+        return;
+    }
+
+    info!(
+        "{} managed path(s) are no longer part of any mapping, removing them",
+        orphans.len()
+    );
+
+    // Relative to their database folder, e.g. `/ext/subghz`
+    let mut databases: BTreeMap<PathBuf, BTreeSet<PathBuf>> = BTreeMap::new();
+
+    for orphan in &orphans {
+        let database = orphan.components().take(3).collect::<PathBuf>();
+
+        if let Ok(relative) = orphan.strip_prefix(&database)
+            && !relative.as_os_str().is_empty()
+        {
+            databases
+                .entry(database.clone())
+                .or_default()
+                .insert(relative.to_path_buf());
+        }
+
+        managed.remove(orphan);
+    }
+
+    for (database, paths) in databases {
+        // Folders above the orphans, including the old namespace. Ones still in use stay.
+        let folders = paths
             .iter()
-            .filter_map(|change| match change {
-                gix::diff::tree_with_rewrites::Change::Addition { location, .. } => {
-                    let location = location.to_str().unwrap();
-                    if !search.is_included(location, Some(false)) {
-                        return None;
-                    }
-                    Some(Op::Copy(PathBuf::from(location)))
+            .flat_map(|path| path.ancestors().skip(1))
+            .filter(|folder| !folder.as_os_str().is_empty())
+            .filter(|folder| {
+                let folder = database.join(folder);
+
+                !uploaded.owns(&folder)
+                    && !destinations
+                        .iter()
+                        .any(|destination| Path::new(destination).starts_with(&folder))
+            })
+            .map(Path::to_path_buf)
+            .collect::<BTreeSet<_>>();
+
+        ops.push(Op::Mapping(
+            String::new(),
+            database.to_string_lossy().into_owned(),
+        ));
+        ops.extend(
+            paths
+                .iter()
+                .filter(|path| !folders.contains(*path))
+                .map(|file| Op::Remove(file.clone(), Reason::RemoteOnly)),
+        );
+        // Children first, only removed once nothing the user placed is left inside
+        ops.extend(
+            folders
+                .into_iter()
+                .rev()
+                .map(|folder| Op::Remove(folder, Reason::Emptied)),
+        );
+    }
+}
+
+/// Names of the folders directly inside `destination` that belong to other mappings
+fn nested_namespaces<'a>(
+    destinations: &'a [String],
+    destination: &'a str,
+) -> impl Iterator<Item = &'a str> {
+    destinations.iter().filter_map(move |other| {
+        other
+            .strip_prefix(destination)?
+            .strip_prefix('/')
+            .filter(|namespace| !namespace.is_empty())
+    })
+}
+
+fn git_diff(
+    remote_commit: Commit<'_>,
+    lcd: &str,
+    local_paths: &[(PathBuf, u32)],
+    ops: &mut Vec<Op>,
+    search: &mut Pathspec,
+) -> Result<()> {
+    use gix::diff::tree_with_rewrites::Change;
+
+    // Operations are relative to the mapping roots
+    let relative = |location: &str| {
+        let location = Path::new(location);
+        location.strip_prefix(lcd).unwrap_or(location).to_path_buf()
+    };
+
+    let mut included = |location: &BStr| {
+        let location = location.to_str().unwrap();
+        search
+            .is_included(location, Some(false))
+            .then(|| relative(location))
+    };
+
+    let mut files = vec![];
+    // Files that did not exist on the remote before, and ones that are gone after
+    let mut added = HashSet::new();
+    let mut gone = vec![];
+
+    for change in diff_from_head(remote_commit)? {
+        // Folders are reported along with their files, they are handled below
+        if change.entry_mode().is_tree() {
+            continue;
+        }
+
+        match change {
+            Change::Addition { location, .. } => {
+                if let Some(path) = included(location.as_ref()) {
+                    added.insert(path.clone());
+                    files.push(Op::Copy(path, Reason::GitDiff));
                 }
-                gix::diff::tree_with_rewrites::Change::Deletion { location, .. } => {
-                    let location = location.to_str().unwrap();
-                    if !search.is_included(location, Some(false)) {
-                        return None;
-                    }
-                    Some(Op::Remove(PathBuf::from(location)))
+            }
+            Change::Modification { location, .. } => {
+                if let Some(path) = included(location.as_ref()) {
+                    files.push(Op::Copy(path, Reason::GitDiff));
                 }
-                gix::diff::tree_with_rewrites::Change::Modification { location, .. } => {
-                    let location = location.to_str().unwrap();
-                    if !search.is_included(location, Some(false)) {
-                        return None;
-                    }
-                    Some(Op::Copy(PathBuf::from(location)))
+            }
+            Change::Deletion { location, .. } => {
+                if let Some(path) = included(location.as_ref()) {
+                    gone.push(path.clone());
+                    files.push(Op::Remove(path, Reason::GitDiff));
                 }
-                _ => unreachable!("rewrites are disabled"),
-            }),
+            }
+            Change::Rewrite {
+                source_location,
+                location,
+                copy,
+                ..
+            } => {
+                let from = (!copy)
+                    .then(|| included(source_location.as_ref()))
+                    .flatten();
+                let to = included(location.as_ref());
+
+                if let Some(from) = &from {
+                    gone.push(from.clone());
+                }
+                if let Some(to) = &to {
+                    added.insert(to.clone());
+                }
+
+                // A move into or out of this mapping is a plain copy or remove here, the
+                // other mapping sees the opposite
+                match (from, to) {
+                    (Some(from), Some(to)) => files.push(Op::Rename(from, to, Reason::GitDiff)),
+                    (None, Some(to)) => files.push(Op::Copy(to, Reason::GitDiff)),
+                    (Some(from), None) => files.push(Op::Remove(from, Reason::GitDiff)),
+                    (None, None) => {}
+                }
+            }
+        }
+    }
+
+    // Folders of every file before and after the upload, the mapping root is created separately
+    let folders = |paths: &mut dyn Iterator<Item = &Path>| {
+        paths
+            .flat_map(|path| path.ancestors().skip(1))
+            .filter(|folder| !folder.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .collect::<BTreeSet<_>>()
+    };
+
+    let after = folders(&mut local_paths.iter().map(|(path, _)| path.as_path()));
+    let before = folders(
+        &mut local_paths
+            .iter()
+            .map(|(path, _)| path.as_path())
+            .filter(|path| !added.contains(*path))
+            .chain(gone.iter().map(PathBuf::as_path)),
+    );
+
+    // New folders parents first like emit_create_subtree, then the files, then emptied folders
+    // children first
+    ops.extend(
+        after
+            .difference(&before)
+            .map(|folder| Op::CreateDir(folder.clone(), Reason::GitDiff)),
+    );
+    ops.extend(files);
+    ops.extend(
+        before
+            .difference(&after)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .map(|folder| Op::Remove(folder.clone(), Reason::Emptied)),
     );
 
     Ok(())
 }
 
 fn walking_diff<P: AsRef<Path> + Sync>(
-    cli: &mut SerialRpcTransport,
+    cli: &mut impl FsReadDir,
     local_paths: &[(P, u32)],
     local_root: impl AsRef<Path>,
     remote_root: impl AsRef<Path>,
-    remote_ignore: &'static [&'static str],
+    remote_ignore: &[&str],
+    managed: &ManagedFiles,
     ops: &mut Vec<Op>,
 ) -> Result<()> {
     info!("Creating local tree");
@@ -253,7 +667,209 @@ fn walking_diff<P: AsRef<Path> + Sync>(
                 diff
             })
         },
+        |relative| managed.owns(remote_root.join(relative)),
     )?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gix::objs::tree::{Entry, EntryKind};
+
+    /// Writes a tree with the given files, each containing its own path
+    fn write_tree(repo: &gix::Repository, files: &[&str]) -> ObjectId {
+        let mut entries = vec![];
+        let mut folders: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+
+        for file in files {
+            match file.split_once('/') {
+                Some((folder, rest)) => folders.entry(folder).or_default().push(rest),
+                None => entries.push(Entry {
+                    mode: EntryKind::Blob.into(),
+                    filename: (*file).into(),
+                    oid: repo.write_blob(file.as_bytes()).unwrap().detach(),
+                }),
+            }
+        }
+
+        for (folder, files) in folders {
+            entries.push(Entry {
+                mode: EntryKind::Tree.into(),
+                filename: folder.into(),
+                oid: write_tree(repo, &files),
+            });
+        }
+
+        entries.sort();
+
+        repo.write_object(gix::objs::Tree { entries })
+            .unwrap()
+            .detach()
+    }
+
+    fn commit(repo: &gix::Repository, files: &[&str], parents: &[ObjectId]) -> ObjectId {
+        let signature = gix::actor::SignatureRef {
+            name: "flippy".into(),
+            email: "flippy@localhost".into(),
+            time: "0 +0000",
+        };
+        let tree = write_tree(repo, files);
+
+        repo.commit_as(
+            signature,
+            signature,
+            "HEAD",
+            "test",
+            tree,
+            parents.iter().copied(),
+        )
+        .unwrap()
+        .detach()
+    }
+
+    #[test]
+    fn git_diff_orders_folders_around_files() {
+        let dir = std::env::temp_dir().join(format!("flippy-git-diff-{}", std::process::id()));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        let repo = gix::init_bare(&dir).unwrap();
+
+        let before = commit(
+            &repo,
+            &["subghz/a.sub", "subghz/old/deep/x.sub", "readme.md"],
+            &[],
+        );
+        commit(
+            &repo,
+            &["subghz/a.sub", "subghz/new/deep/y.sub", "readme.md"],
+            &[before],
+        );
+
+        let index = repo.index_or_empty().unwrap();
+        let mut spec = repo
+            .pathspec(
+                false,
+                ["subghz"],
+                false,
+                &index,
+                gix::worktree::stack::state::attributes::Source::IdMapping,
+            )
+            .unwrap();
+
+        // Paths of HEAD below the mapping root
+        let local_paths = [
+            (PathBuf::from("a.sub"), 5),
+            (PathBuf::from("new/deep/y.sub"), 21),
+        ];
+
+        let mut ops = vec![];
+        git_diff(
+            repo.find_commit(before).unwrap(),
+            "subghz",
+            &local_paths,
+            &mut ops,
+            &mut spec,
+        )
+        .unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let ops = ops
+            .into_iter()
+            .map(|op| match op {
+                Op::CreateDir(path, Reason::GitDiff) => ("mkdir", path),
+                Op::Copy(path, Reason::GitDiff) => ("copy", path),
+                Op::Remove(path, Reason::GitDiff) => ("rm", path),
+                Op::Remove(path, Reason::Emptied) => ("rmdir", path),
+                op => panic!("unexpected {op:?}"),
+            })
+            .collect::<Vec<_>>();
+
+        let (folders, rest) = ops.split_at(2);
+        let (files, emptied) = rest.split_at(2);
+
+        assert_eq!(
+            folders,
+            [("mkdir", "new".into()), ("mkdir", "new/deep".into())]
+        );
+        assert_eq!(
+            files.iter().cloned().collect::<BTreeSet<_>>(),
+            BTreeSet::from([
+                ("copy", "new/deep/y.sub".into()),
+                ("rm", "old/deep/x.sub".into()),
+            ])
+        );
+        assert_eq!(
+            emptied,
+            [("rmdir", "old/deep".into()), ("rmdir", "old".into())]
+        );
+    }
+
+    #[test]
+    fn orphans_of_an_old_namespace_are_removed() {
+        let mut managed = ManagedFiles::default();
+        managed.insert("/ext/subghz/old", "a.sub");
+        managed.insert("/ext/subghz/old", "dir/b.sub");
+        managed.insert("/ext/subghz/new", "a.sub");
+
+        let mut uploaded = ManagedFiles::default();
+        uploaded.insert("/ext/subghz/new", "a.sub");
+
+        let mut ops = vec![];
+        remove_orphans(
+            &mut managed,
+            &uploaded,
+            &["/ext/subghz/new".to_string()],
+            &mut ops,
+        );
+
+        assert_eq!(managed.paths, uploaded.paths);
+
+        let Some((Op::Mapping(local, remote), ops)) = ops.split_first() else {
+            panic!("expected a mapping marker first, got {ops:?}");
+        };
+        assert_eq!((local.as_str(), remote.as_str()), ("", "/ext/subghz"));
+
+        let ops = ops
+            .iter()
+            .map(|op| match op {
+                Op::Remove(path, Reason::RemoteOnly) => ("rm", path.clone()),
+                Op::Remove(path, Reason::Emptied) => ("rmdir", path.clone()),
+                op => panic!("unexpected {op:?}"),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            ops,
+            [
+                ("rm", "old/a.sub".into()),
+                ("rm", "old/dir/b.sub".into()),
+                ("rmdir", "old/dir".into()),
+                ("rmdir", "old".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_to_remove_when_everything_is_uploaded() {
+        let mut managed = ManagedFiles::default();
+        managed.insert("/ext/subghz", "a/b.sub");
+
+        let mut uploaded = ManagedFiles::default();
+        uploaded.insert("/ext/subghz", "a/b.sub");
+
+        let mut ops = vec![];
+        remove_orphans(
+            &mut managed,
+            &uploaded,
+            &["/ext/subghz".to_string()],
+            &mut ops,
+        );
+
+        assert!(ops.is_empty());
+        assert_eq!(managed.paths, uploaded.paths);
+    }
+}
@@ -0,0 +1,269 @@
+//! Reviewable upload plans. `flippy upload --plan` writes one, `flippy apply` executes it.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use gix::open;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::upload::RemoteState,
+    source,
+    types::{
+        flip::Flip, managed_files::ManagedFiles, remote_sync_file::SyncFile, repository::Source,
+    },
+    walking_diff::diff::{Op, Reason},
+};
+
+const VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Plan {
+    pub version: u8,
+
+    /// Digest of the sync file and managed files list the plan was made against
+    pub base: String,
+
+    pub repositories: Vec<PlannedRepository>,
+
+    /// Sync file to write once every operation succeeded, hex encoded
+    pub sync_file: String,
+
+    /// Managed files list to write once every operation succeeded
+    pub managed_files: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlannedRepository {
+    pub name: String,
+
+    /// Clone in the store
+    pub path: PathBuf,
+
+    /// Commit the plan was made from, files are copied from the worktree
+    pub head: String,
+
+    /// `head` is a content hash, the repository is a local folder or an archive
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub manifest: bool,
+
+    pub mappings: Vec<PlannedMapping>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlannedMapping {
+    pub local_root: String,
+    pub remote_root: String,
+    pub operations: Vec<PlannedOp>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum PlannedOp {
+    Copy {
+        path: PathBuf,
+        source: PathBuf,
+        destination: PathBuf,
+        size: u64,
+        reason: Reason,
+    },
+    CreateDir {
+        path: PathBuf,
+        destination: PathBuf,
+        reason: Reason,
+    },
+    Remove {
+        path: PathBuf,
+        destination: PathBuf,
+        reason: Reason,
+    },
+    /// Moved on the remote, `source` and `destination` are both remote paths
+    Rename {
+        from: PathBuf,
+        path: PathBuf,
+        source: PathBuf,
+        destination: PathBuf,
+        reason: Reason,
+    },
+    Unmanaged {
+        path: PathBuf,
+        destination: PathBuf,
+    },
+}
+
+impl Plan {
+    pub fn new(
+        flip: &Flip,
+        operations: &[Op],
+        state: &RemoteState,
+        sync_file: &SyncFile,
+        managed: &ManagedFiles,
+    ) -> Result<Self> {
+        let mut repositories: Vec<PlannedRepository> = vec![];
+
+        for op in operations {
+            if let Op::Repo(path) = op {
+                let (name, repo) = flip
+                    .repositories
+                    .iter()
+                    .find(|(_, repo)| repo.local_path(flip).is_ok_and(|local| local == *path))
+                    .context("operation for a repository that is not in flip.toml")?;
+
+                let manifest = !matches!(repo.source()?, Source::Git(_));
+
+                repositories.push(PlannedRepository {
+                    name: name.clone(),
+                    path: path.clone(),
+                    head: head(path, manifest)?,
+                    manifest,
+                    mappings: vec![],
+                });
+                continue;
+            }
+
+            let repo = repositories
+                .last_mut()
+                .context("operation outside of a repository")?;
+
+            if let Op::Mapping(local_root, remote_root) = op {
+                repo.mappings.push(PlannedMapping {
+                    local_root: local_root.clone(),
+                    remote_root: remote_root.clone(),
+                    operations: vec![],
+                });
+                continue;
+            }
+
+            let mapping = repo
+                .mappings
+                .last_mut()
+                .context("operation outside of a mapping")?;
+
+            let destination = |path: &Path| Path::new(&mapping.remote_root).join(path);
+
+            let planned = match op {
+                Op::Copy(path, reason) => {
+                    let source = repo.path.join(&mapping.local_root).join(path);
+                    let size = std::fs::metadata(&source)?.len();
+
+                    PlannedOp::Copy {
+                        path: path.clone(),
+                        destination: destination(path),
+                        source,
+                        size,
+                        reason: *reason,
+                    }
+                }
+                Op::CreateDir(path, reason) => PlannedOp::CreateDir {
+                    path: path.clone(),
+                    destination: destination(path),
+                    reason: *reason,
+                },
+                Op::Remove(path, reason) => PlannedOp::Remove {
+                    path: path.clone(),
+                    destination: destination(path),
+                    reason: *reason,
+                },
+                Op::Rename(from, path, reason) => PlannedOp::Rename {
+                    source: destination(from),
+                    destination: destination(path),
+                    from: from.clone(),
+                    path: path.clone(),
+                    reason: *reason,
+                },
+                Op::Unmanaged(path) => PlannedOp::Unmanaged {
+                    path: path.clone(),
+                    destination: destination(path),
+                },
+                Op::Repo(..) | Op::Mapping(..) => unreachable!("handled above"),
+            };
+
+            mapping.operations.push(planned);
+        }
+
+        Ok(Self {
+            version: VERSION,
+            base: state.digest(),
+            repositories,
+            sync_file: hex::encode(sync_file.serialize()),
+            managed_files: managed.paths.iter().cloned().collect(),
+        })
+    }
+
+    /// Checks the plan still applies to the remote and the store, then turns it back into
+    /// operations.
+    pub fn operations(&self, state: &RemoteState) -> Result<Vec<Op>> {
+        if self.version != VERSION {
+            bail!(
+                "unsupported plan version {}, expected {VERSION}",
+                self.version
+            );
+        }
+
+        if state.digest() != self.base {
+            bail!(
+                "the sync file on the device changed since this plan was made, please create a new plan"
+            );
+        }
+
+        let mut operations = vec![];
+
+        for repo in &self.repositories {
+            let head = head(&repo.path, repo.manifest)?;
+
+            if head != repo.head {
+                bail!(
+                    "repository `{}` moved from {} to {head} since this plan was made, please create a new plan",
+                    repo.name,
+                    repo.head
+                );
+            }
+
+            operations.push(Op::Repo(repo.path.clone()));
+
+            for mapping in &repo.mappings {
+                operations.push(Op::Mapping(
+                    mapping.local_root.clone(),
+                    mapping.remote_root.clone(),
+                ));
+
+                operations.extend(mapping.operations.iter().map(|op| match op {
+                    PlannedOp::Copy { path, reason, .. } => Op::Copy(path.clone(), *reason),
+                    PlannedOp::CreateDir { path, reason, .. } => {
+                        Op::CreateDir(path.clone(), *reason)
+                    }
+                    PlannedOp::Remove { path, reason, .. } => Op::Remove(path.clone(), *reason),
+                    PlannedOp::Rename {
+                        from, path, reason, ..
+                    } => Op::Rename(from.clone(), path.clone(), *reason),
+                    PlannedOp::Unmanaged { path, .. } => Op::Unmanaged(path.clone()),
+                }));
+            }
+        }
+
+        Ok(operations)
+    }
+
+    pub fn sync_file(&self) -> Result<Vec<u8>> {
+        let data = hex::decode(&self.sync_file)?;
+
+        // Never write something we could not read back
+        SyncFile::deserialize(&data).context("plan contains an invalid sync file")?;
+
+        Ok(data)
+    }
+
+    pub fn managed_files(&self) -> ManagedFiles {
+        ManagedFiles {
+            paths: self.managed_files.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Commit of a clone, or the content hash of a folder that is not one
+fn head(path: &Path, manifest: bool) -> Result<String> {
+    Ok(match manifest {
+        true => hex::encode(source::manifest(path, &source::files(path)?)?),
+        false => open(path)?.head_id()?.to_string(),
+    })
+}
@@ -1,6 +1,14 @@
 use anyhow::{Result, bail};
 use cliclack::select;
-use flipper_rpc::transport::serial::{list_flipper_ports, rpc::SerialRpcTransport};
+use flipper_rpc::{
+    rpc::{req::Request, res::Response},
+    transport::{
+        Transport, TransportRaw,
+        serial::{list_flipper_ports, rpc::SerialRpcTransport},
+    },
+};
+
+use crate::types::directory::Target;
 
 pub fn pick_cli() -> Result<SerialRpcTransport> {
     let ports = list_flipper_ports()?;
@@ -26,3 +34,35 @@ pub fn pick_cli() -> Result<SerialRpcTransport> {
 
     Ok(cli)
 }
+
+/// Hardware target of the connected flipper, from the `hardware_target` key of its device info
+pub fn hardware_target(cli: &mut SerialRpcTransport) -> Result<Target> {
+    cli.send(Request::SystemDeviceInfo)?;
+
+    let mut target = None;
+
+    // Device info is streamed as one key and value per message
+    loop {
+        let response = cli.receive_raw()?;
+        let has_next = response.has_next;
+
+        if let Response::SystemDeviceInfo(info) = Response::from(response)
+            && info.key == "hardware_target"
+        {
+            target = Some(info.value);
+        }
+
+        if !has_next {
+            break;
+        }
+    }
+
+    match target.as_deref() {
+        Some("7") => Ok(Target::F7),
+        Some("18") => Ok(Target::F18),
+        Some(other) => bail!("unknown hardware target `{other}`, pass --target to choose one"),
+        None => {
+            bail!("the flipper did not report its hardware target, pass --target to choose one")
+        }
+    }
+}
@@ -1,6 +1,11 @@
 //! Swiped from gitoxide-core with some modifications for my usecase
 
+pub mod auth;
+pub mod checkout;
 pub mod clone;
 pub mod diff;
 pub mod fetch;
+pub mod pin;
 pub mod remote;
+pub mod shallow;
+pub mod sparse;
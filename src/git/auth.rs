@@ -0,0 +1,84 @@
+//! Credentials for private repositories, see [`Auth`]. SSH remotes authenticate through `ssh`
+//! itself (agent, `~/.ssh/config`) and need none of this.
+
+use gix::{
+    Remote,
+    credentials::{
+        Program,
+        helper::{Action, Cascade, NextAction},
+        protocol,
+    },
+    prompt,
+    remote::{Connection, Direction},
+    sec::identity::Account,
+};
+
+use crate::types::repository::Auth;
+
+/// Credentials callback for gix connections. Nothing is read before the remote asks for it, so
+/// commands that never touch the network do not need the variables to be set.
+#[allow(clippy::result_large_err)] // protocol::Error is gix's
+pub fn credentials(auth: Auth) -> impl FnMut(Action) -> protocol::Result + Send + Sync + 'static {
+    move |mut action| {
+        if let Some(helper) = &auth.helper {
+            let mut cascade = Cascade {
+                programs: vec![Program::from_custom_definition(helper.as_str())],
+                ..Default::default()
+            };
+
+            // Never fall back to a terminal prompt, it would end up under the progress bars
+            return cascade.invoke(
+                action,
+                prompt::Options {
+                    askpass: None,
+                    mode: prompt::Mode::Disable,
+                },
+            );
+        }
+
+        // Storing or erasing only concerns helpers
+        let Some(ctx) = action.context_mut() else {
+            return Ok(None);
+        };
+
+        ctx.destructure_url_in_place(false)?;
+
+        let username = auth
+            .username
+            .clone()
+            .or_else(|| ctx.username.clone())
+            .ok_or_else(|| missing("no username, set `username` in `auth` or put it in the URL"))?;
+
+        let variable = auth.password_env.as_deref().unwrap_or_default();
+        let password = std::env::var(variable)
+            .map_err(|_| missing(&format!("environment variable `{variable}` is not set")))?;
+
+        Ok(Some(protocol::Outcome {
+            identity: Account {
+                username,
+                password,
+                oauth_refresh_token: None,
+            },
+            next: NextAction::from(ctx.clone()),
+        }))
+    }
+}
+
+/// Connects for fetching, with the credentials of `auth` when given
+pub fn connect<'a, 'repo>(
+    remote: &'a Remote<'repo>,
+    auth: Option<&Auth>,
+) -> anyhow::Result<
+    Connection<'a, 'repo, Box<dyn gix::protocol::transport::client::Transport + Send>>,
+> {
+    let connection = remote.connect(Direction::Fetch)?;
+
+    Ok(match auth {
+        Some(auth) => connection.with_credentials(credentials(auth.clone())),
+        None => connection,
+    })
+}
+
+fn missing(message: &str) -> protocol::Error {
+    gix::credentials::helper::Error::Io(std::io::Error::other(message.to_string())).into()
+}
@@ -0,0 +1,269 @@
+//! Moves the worktree of an existing clone to another commit, like `git checkout --force`, only
+//! materializing what the sparse patterns include
+
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{Context, Result};
+use gix::{
+    NestedProgress, ObjectId, Repository,
+    bstr::{BStr, ByteSlice},
+    index::{Entry, entry::Flags},
+    progress::{Progress, bytes, count},
+    refs::{
+        FullName, Target,
+        transaction::{Change, LogChange, PreviousValue, RefEdit, RefLog},
+    },
+    worktree::stack::state::attributes::Source,
+};
+
+use crate::git;
+
+/// Where HEAD points after the checkout
+pub enum Head<'a> {
+    /// `refs/heads/<name>`, created or moved to the commit
+    Branch(&'a str),
+    Detached,
+}
+
+pub fn checkout<P>(repo: &Repository, id: ObjectId, head: Head<'_>, mut progress: P) -> Result<()>
+where
+    P: NestedProgress,
+    P::SubProgress: 'static,
+{
+    let workdir = repo
+        .workdir()
+        .context("store repositories must not be bare")?
+        .to_path_buf();
+
+    let tree = repo.find_commit(id)?.tree_id()?.detach();
+
+    let old = repo.index_or_empty()?;
+    let mut index = repo.index_from_tree(&tree)?;
+
+    git::sparse::apply(repo, &mut index)?;
+
+    // Files that are no longer tracked at `id`, or are now outside of the sparse patterns, would
+    // otherwise linger in the worktree and get uploaded by walking_diff
+    let materialized = |entry: &Entry| !entry.flags.contains(Flags::SKIP_WORKTREE);
+    let wanted: HashSet<&BStr> = index
+        .entries()
+        .iter()
+        .filter(|e| materialized(e))
+        .map(|e| e.path(&index))
+        .collect();
+
+    for entry in old.entries().iter().filter(|e| materialized(e)) {
+        let path = entry.path(&old);
+        if !wanted.contains(path) {
+            remove_with_empty_parents(&workdir, &gix::path::from_bstr(path))?;
+        }
+    }
+
+    let mut opts = repo.checkout_options(Source::IdMapping)?;
+    opts.overwrite_existing = true;
+
+    let mut files = progress.add_child("checkout");
+    let mut written = progress.add_child("writing");
+
+    files.init(Some(wanted.len()), count("files"));
+    written.init(None, bytes());
+
+    let outcome = gix::worktree::state::checkout(
+        &mut index,
+        &workdir,
+        repo.objects.clone().into_arc()?,
+        &files,
+        &written,
+        &gix::interrupt::IS_INTERRUPTED,
+        opts,
+    )?;
+
+    let collisions = outcome.collisions;
+    let errors = outcome.errors;
+
+    if !(collisions.is_empty() && errors.is_empty()) {
+        let mut messages = Vec::new();
+        if !errors.is_empty() {
+            messages.push(format!("kept going through {} errors(s)", errors.len()));
+            for record in errors {
+                progress.info(format!("error: {}: {}", record.path, record.error));
+            }
+        }
+        if !collisions.is_empty() {
+            messages.push(format!("encountered {} collision(s)", collisions.len()));
+            for col in collisions {
+                progress.info(format!(
+                    "error: {}: collision ({:?})",
+                    col.path, col.error_kind
+                ));
+            }
+        }
+        progress.fail(format!(
+            "One or more errors occurred - checkout is incomplete: {}",
+            messages.join(", "),
+        ));
+    }
+
+    index.write(Default::default())?;
+
+    let log = |message: String| LogChange {
+        mode: RefLog::AndReference,
+        force_create_reflog: false,
+        message: message.into(),
+    };
+
+    let target = match head {
+        Head::Branch(branch) => {
+            let name: FullName = format!("refs/heads/{branch}").try_into()?;
+
+            repo.edit_reference(RefEdit {
+                change: Change::Update {
+                    log: log(format!("flippy: checkout {id}")),
+                    expected: PreviousValue::Any,
+                    new: Target::Object(id),
+                },
+                name: name.clone(),
+                deref: false,
+            })?;
+
+            Target::Symbolic(name)
+        }
+        Head::Detached => Target::Object(id),
+    };
+
+    repo.edit_reference(RefEdit {
+        change: Change::Update {
+            log: log(format!("flippy: moving to {id}")),
+            expected: PreviousValue::Any,
+            new: target,
+        },
+        name: "HEAD".try_into()?,
+        deref: false,
+    })?;
+
+    progress.done(format!("checked out {}", id.to_hex_with_len(7)));
+
+    Ok(())
+}
+
+/// Checks out HEAD again, e.g. after the sparse patterns changed. A no-op on empty repositories.
+pub fn checkout_head<P>(repo: &Repository, progress: P) -> Result<()>
+where
+    P: NestedProgress,
+    P::SubProgress: 'static,
+{
+    let head = repo.head()?;
+
+    let Some(id) = head.id() else {
+        return Ok(());
+    };
+
+    let branch = head
+        .referent_name()
+        .and_then(|name| name.as_bstr().strip_prefix(b"refs/heads/"))
+        .map(|branch| branch.to_str_lossy().into_owned());
+
+    let head = match &branch {
+        Some(branch) => Head::Branch(branch),
+        None => Head::Detached,
+    };
+
+    checkout(repo, id.detach(), head, progress)
+}
+
+fn remove_with_empty_parents(workdir: &Path, relative: &Path) -> Result<()> {
+    match std::fs::remove_file(workdir.join(relative)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+
+    for parent in relative.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() || std::fs::remove_dir(workdir.join(parent)).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether HEAD already is `head` at `id`, so a checkout can be skipped
+pub fn is_checked_out(repo: &Repository, id: ObjectId, head: &Head<'_>) -> Result<bool> {
+    let current = repo.head()?;
+
+    if current.id().map(|i| i.detach()) != Some(id) {
+        return Ok(false);
+    }
+
+    Ok(match (head, current.referent_name()) {
+        (Head::Branch(branch), Some(name)) => {
+            name.as_bstr().strip_prefix(b"refs/heads/") == Some(branch.as_bytes())
+        }
+        (Head::Detached, None) => true,
+        _ => false,
+    })
+}
+
+/// `branch` and its upstream both have commits the other does not, upstream history was
+/// rewritten
+pub struct Diverged {
+    pub branch: String,
+    pub local: ObjectId,
+    pub upstream: ObjectId,
+
+    /// Commits only reachable from `local`, lost on reset
+    pub dropped: usize,
+}
+
+/// Moves `branch` and the worktree to `upstream` when that does not drop any commit
+pub fn fast_forward<P>(
+    repo: &Repository,
+    branch: &str,
+    upstream: ObjectId,
+    progress: P,
+) -> Result<Option<Diverged>>
+where
+    P: NestedProgress,
+    P::SubProgress: 'static,
+{
+    let local = repo
+        .find_reference(format!("refs/heads/{branch}").as_str())?
+        .peel_to_id_in_place()?
+        .detach();
+
+    if local == upstream {
+        progress.info(format!("{branch} is up to date"));
+        return Ok(None);
+    }
+
+    // Unrelated histories have no merge base at all
+    if repo
+        .merge_base(local, upstream)
+        .ok()
+        .map(|base| base.detach())
+        != Some(local)
+    {
+        let dropped = repo
+            .rev_walk([local])
+            .with_hidden([upstream])
+            .all()?
+            .count();
+
+        return Ok(Some(Diverged {
+            branch: branch.to_string(),
+            local,
+            upstream,
+            dropped,
+        }));
+    }
+
+    progress.info(format!(
+        "fast-forwarding {branch} {}..{}",
+        local.to_hex_with_len(7),
+        upstream.to_hex_with_len(7)
+    ));
+
+    checkout(repo, upstream, Head::Branch(branch), progress)?;
+
+    Ok(None)
+}
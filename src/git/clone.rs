@@ -1,13 +1,31 @@
 use std::{borrow::Cow, ffi::OsStr};
 
 use anyhow::Context;
-use gix::{NestedProgress, Repository, prepare_clone, remote::fetch::Status};
+use gix::{
+    NestedProgress, Repository,
+    bstr::BString,
+    prepare_clone,
+    remote::fetch::{Shallow, Status, Tags},
+};
 
-use crate::git::fetch::print_updates;
+use crate::{
+    git::{
+        auth,
+        checkout::checkout_head,
+        fetch::{self, print_updates},
+        pin,
+        shallow::CLONE_DEPTH,
+        sparse,
+    },
+    types::repository::{Auth, Pin},
+};
 
 pub fn clone<P>(
     url: impl AsRef<OsStr>,
     directory: Option<impl Into<std::path::PathBuf>>,
+    pin: Option<&Pin>,
+    auth: Option<&Auth>,
+    sparse: Option<&[BString]>,
     mut progress: P,
 ) -> anyhow::Result<Repository>
 where
@@ -28,12 +46,34 @@ where
         |dir| Ok(dir.into()),
     )?;
 
-    let mut prepare = prepare_clone(url, directory)?;
+    let prepare = match pin {
+        Some(Pin::Branch(branch)) => {
+            prepare_clone(url, directory)?.with_ref_name(Some(branch.as_str()))?
+        }
+        // Tags are only fetched when they point into a fetched branch otherwise
+        Some(Pin::Tag(_)) => prepare_clone(url, directory)?
+            .configure_remote(|remote| Ok(remote.with_fetch_tags(Tags::All))),
+        _ => prepare_clone(url, directory)?,
+    };
+
+    // History is deepened on demand by `upload`, see git::shallow
+    let mut prepare = prepare.with_shallow(Shallow::DepthAtRemote(CLONE_DEPTH));
 
-    let (mut checkout, fetch_outcome) =
+    if let Some(auth) = auth.cloned() {
+        prepare = prepare.configure_connection(move |connection| {
+            connection.set_credentials(auth::credentials(auth.clone()));
+            Ok(())
+        });
+    }
+
+    let (prepared, fetch_outcome) =
         prepare.fetch_then_checkout(&mut progress, &gix::interrupt::IS_INTERRUPTED)?;
 
-    let (repo, outcome) = checkout.main_worktree(&mut progress, &gix::interrupt::IS_INTERRUPTED)?;
+    // Checked out by hand, main_worktree would write every file
+    let repo = prepared.persist();
+    fetch::record_time(&repo)?;
+    sparse::write(&repo, sparse)?;
+    checkout_head(&repo, &mut progress)?;
 
     match fetch_outcome.status {
         Status::NoPackReceived { .. } => {
@@ -59,30 +99,8 @@ where
         }
     }
 
-    let collisions = outcome.collisions;
-    let errors = outcome.errors;
-
-    if !(collisions.is_empty() && errors.is_empty()) {
-        let mut messages = Vec::new();
-        if !errors.is_empty() {
-            messages.push(format!("kept going through {} errors(s)", errors.len()));
-            for record in errors {
-                progress.info(format!("error: {}: {}", record.path, record.error));
-            }
-        }
-        if !collisions.is_empty() {
-            messages.push(format!("encountered {} collision(s)", collisions.len()));
-            for col in collisions {
-                progress.info(format!(
-                    "error: {}: collision ({:?})",
-                    col.path, col.error_kind
-                ));
-            }
-        }
-        progress.fail(format!(
-            "One or more errors occurred - checkout is incomplete: {}",
-            messages.join(", "),
-        ));
+    if let Some(pin) = pin {
+        pin::apply(&repo, pin, auth, &mut progress)?;
     }
 
     progress.done("success".to_string());
@@ -3,7 +3,7 @@
 use anyhow::Result;
 use gix::{
     Commit,
-    diff::{Options, tree_with_rewrites::Change},
+    diff::{Options, Rewrites, tree_with_rewrites::Change},
 };
 
 pub fn diff_from_head(commit: Commit<'_>) -> Result<Vec<Change>> {
@@ -12,7 +12,11 @@ pub fn diff_from_head(commit: Commit<'_>) -> Result<Vec<Change>> {
     let remote_tree = commit.tree()?;
     let local_tree = repo.head_tree()?;
 
-    let options = Options::default().with_rewrites(None);
+    // Only exact renames, a file that also changed has to be copied anyway
+    let options = Options::default().with_rewrites(Some(Rewrites {
+        percentage: None,
+        ..Default::default()
+    }));
 
     Ok(repo.diff_tree_to_tree(&remote_tree, &local_tree, Some(options))?)
 }
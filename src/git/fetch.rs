@@ -1,28 +1,65 @@
-use crate::git;
+use crate::{
+    git::{
+        self,
+        checkout::{Diverged, Head, checkout, fast_forward},
+    },
+    types::repository::{Auth, Pin},
+};
+use anyhow::Context;
 use gix::{
+    bstr::ByteSlice,
     prelude::ObjectIdExt,
     refspec::match_group::validate::Fix,
-    remote::fetch::{Status, refs::update::TypeChange},
+    remote::fetch::{Status, Tags, refs::update::TypeChange},
 };
 
+/// Time of the last fetch, gix only writes reflogs when a ref moved
+const FETCHED_FILE: &str = "flippy_fetched";
+
+pub fn record_time(repo: &gix::Repository) -> anyhow::Result<()> {
+    std::fs::write(
+        repo.git_dir().join(FETCHED_FILE),
+        jiff::Timestamp::now().to_string(),
+    )?;
+
+    Ok(())
+}
+
+/// None for clones made before fetches were recorded
+pub fn last_time(repo: &gix::Repository) -> anyhow::Result<Option<jiff::Timestamp>> {
+    match std::fs::read_to_string(repo.git_dir().join(FETCHED_FILE)) {
+        Ok(time) => Ok(Some(time.trim().parse()?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
 pub fn fetch<P>(
     repo: gix::Repository,
     remote: Option<String>,
+    pin: Option<&Pin>,
+    auth: Option<&Auth>,
     mut progress: P,
-) -> anyhow::Result<()>
+) -> anyhow::Result<Option<Diverged>>
 where
     P: gix::NestedProgress,
     P::SubProgress: 'static,
 {
-    let remote = git::remote::by_name_or_url(&repo, remote.as_deref())?;
-    let res: gix::remote::fetch::Outcome = remote
-        .connect(gix::remote::Direction::Fetch)?
+    let mut remote = git::remote::by_name_or_url(&repo, remote.as_deref())?;
+
+    // Tags are only fetched when they point into a fetched branch otherwise
+    if let Some(Pin::Tag(_)) = pin {
+        remote = remote.with_fetch_tags(Tags::All);
+    }
+
+    let res: gix::remote::fetch::Outcome = git::auth::connect(&remote, auth)?
+        // Shallow clones keep their boundary and receive every new commit, `upload` deepens
+        // them further when it needs older history
         .prepare_fetch(&mut progress, Default::default())?
-        //.with_shallow(gix::remote::fetch::Shallow::DepthAtRemote(1.try_into()?)) Cannot shallow
-        //clone due to deep remote diffing on the upload command. This also does not save
-        //much data on UberGuidoZ/Flipper
         .receive(&mut progress, &gix::interrupt::IS_INTERRUPTED)?;
 
+    record_time(&repo)?;
+
     let ref_specs = remote.refspecs(gix::remote::Direction::Fetch);
     match res.status {
         Status::NoPackReceived {
@@ -62,9 +99,78 @@ where
             Ok(())
         }
     }?;
-    Ok(())
+
+    let remote_name = remote
+        .name()
+        .map_or_else(|| "origin".to_string(), |name| name.as_bstr().to_string());
+
+    update_head(&repo, &remote_name, pin, auth, progress)
+}
+
+/// Brings HEAD and the worktree up to date with what was just fetched
+fn update_head<P>(
+    repo: &gix::Repository,
+    remote: &str,
+    pin: Option<&Pin>,
+    auth: Option<&Auth>,
+    progress: P,
+) -> anyhow::Result<Option<Diverged>>
+where
+    P: gix::NestedProgress,
+    P::SubProgress: 'static,
+{
+    let current = repo.head_name()?.and_then(|name| {
+        name.as_bstr()
+            .strip_prefix(b"refs/heads/")
+            .map(|branch| branch.to_str_lossy().into_owned())
+    });
+
+    let branch = match (pin, current) {
+        // Following a branch, only ever fast-forward it
+        (Some(Pin::Branch(pinned)), Some(current)) if *pinned == current => current,
+        (Some(pin), _) => {
+            git::pin::apply(repo, pin, auth, progress)?;
+            return Ok(None);
+        }
+        (None, Some(current)) => current,
+        (None, None) => {
+            // Detached by a pin that was removed since, go back to the default branch
+            let default = repo
+                .find_reference(format!("refs/remotes/{remote}/HEAD").as_str())
+                .context("the remote has no default branch")?;
+
+            let branch = default
+                .target()
+                .try_name()
+                .and_then(|name| {
+                    name.as_bstr()
+                        .strip_prefix(format!("refs/remotes/{remote}/").as_bytes())
+                })
+                .context("the default branch of the remote is not a branch")?
+                .to_str_lossy()
+                .into_owned();
+
+            let id = repo
+                .find_reference(format!("refs/remotes/{remote}/{branch}").as_str())?
+                .peel_to_id_in_place()?
+                .detach();
+
+            progress.info(format!("checking out the default branch {branch}"));
+            checkout(repo, id, Head::Branch(&branch), progress)?;
+            return Ok(None);
+        }
+    };
+
+    let upstream = repo
+        .find_reference(format!("refs/remotes/{remote}/{branch}").as_str())
+        .with_context(|| format!("branch `{branch}` does not exist on the remote"))?
+        .peel_to_id_in_place()?
+        .detach();
+
+    fast_forward(repo, &branch, upstream, progress)
 }
 
+#[allow(clippy::collapsible_if)] // Kept close to gitoxide's `print_updates`
 pub fn print_updates<P>(
     repo: &gix::Repository,
     negotiate: &gix::remote::fetch::outcome::Negotiate,
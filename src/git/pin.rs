@@ -0,0 +1,68 @@
+//! Resolves and checks out the branch, tag or commit a repository is pinned to
+
+use anyhow::{Context, Result};
+use gix::{NestedProgress, ObjectId, Repository};
+
+use crate::{
+    git::{
+        self,
+        checkout::{Head, checkout, is_checked_out},
+    },
+    types::repository::{Auth, Pin},
+};
+
+/// Commit `pin` points to in the store clone
+pub fn resolve(repo: &Repository, pin: &Pin, auth: Option<&Auth>) -> Result<ObjectId> {
+    let id = match pin {
+        Pin::Branch(branch) => repo
+            .find_reference(format!("refs/remotes/origin/{branch}").as_str())
+            .with_context(|| format!("branch `{branch}` does not exist on the remote"))?
+            .peel_to_id_in_place()?
+            .detach(),
+        Pin::Tag(tag) => repo
+            .find_reference(format!("refs/tags/{tag}").as_str())
+            .with_context(|| format!("tag `{tag}` does not exist on the remote"))?
+            .peel_to_id_in_place()?
+            .detach(),
+        Pin::Rev(rev) => {
+            // Shallow clones may not reach back far enough yet
+            if let Ok(id) = ObjectId::from_hex(rev.as_bytes()) {
+                git::shallow::deepen_until(repo, id, auth)?;
+            }
+
+            repo.rev_parse_single(rev.as_str())
+                .with_context(|| {
+                    format!("commit `{rev}` was not fetched, is it reachable from a branch or tag?")
+                })?
+                .detach()
+        }
+    };
+
+    Ok(repo
+        .find_object(id)?
+        .peel_to_commit()
+        .with_context(|| format!("{pin} does not point to a commit"))?
+        .id)
+}
+
+/// Moves the worktree to the commit `pin` resolves to, a no-op when it is already there
+pub fn apply<P>(repo: &Repository, pin: &Pin, auth: Option<&Auth>, progress: P) -> Result<()>
+where
+    P: NestedProgress,
+    P::SubProgress: 'static,
+{
+    let id = resolve(repo, pin, auth)?;
+
+    let head = match pin {
+        Pin::Branch(branch) => Head::Branch(branch),
+        Pin::Tag(_) | Pin::Rev(_) => Head::Detached,
+    };
+
+    if is_checked_out(repo, id, &head)? {
+        progress.info(format!("already at {pin} ({})", id.to_hex_with_len(7)));
+        return Ok(());
+    }
+
+    progress.info(format!("checking out {pin} ({})", id.to_hex_with_len(7)));
+    checkout(repo, id, head, progress)
+}
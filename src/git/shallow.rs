@@ -0,0 +1,76 @@
+//! Store clones start with a depth of one, history is only fetched when a diff needs it
+
+use std::num::NonZeroU32;
+
+use anyhow::Result;
+use gix::{ObjectId, Repository, progress::Discard, remote::fetch::Shallow};
+use tracing::info;
+
+use crate::{git, types::repository::Auth};
+
+/// Depth of new clones, only HEAD is needed to upload
+pub const CLONE_DEPTH: NonZeroU32 = NonZeroU32::MIN;
+
+/// Commits to deepen by at first, doubled every round
+const DEEPEN_STEP: u32 = 32;
+
+/// Past this many commits a walking_diff is cheaper than downloading more history
+pub const MAX_DEPTH: u32 = 1024;
+
+/// Outcome of [`deepen_until`]
+pub enum Deepened {
+    Found,
+
+    /// Not within [`MAX_DEPTH`] commits, or not in the history at all
+    NotFound,
+
+    /// The remote could not be reached, e.g. a store restored by `store import` without network
+    Unreachable(anyhow::Error),
+}
+
+/// Deepens a shallow clone until `id` is present, at most [`MAX_DEPTH`] commits
+pub fn deepen_until(repo: &Repository, id: ObjectId, auth: Option<&Auth>) -> Result<Deepened> {
+    let mut depth = 0;
+    let mut step = DEEPEN_STEP;
+
+    while !repo.has_object(id) {
+        if !repo.is_shallow() || depth >= MAX_DEPTH {
+            return Ok(Deepened::NotFound);
+        }
+
+        info!(
+            "Deepening shallow clone by {step} commit(s) to find {}",
+            id.to_hex_with_len(7)
+        );
+
+        let boundary = repo.shallow_commits()?.map(|commits| commits.to_vec());
+
+        let fetched = (|| -> Result<()> {
+            let remote = git::remote::by_name_or_url(repo, None)?;
+
+            git::auth::connect(&remote, auth)?
+                .prepare_fetch(Discard, Default::default())?
+                .with_shallow(Shallow::Deepen(step))
+                .receive(Discard, &gix::interrupt::IS_INTERRUPTED)?;
+
+            Ok(())
+        })();
+
+        if let Err(e) = fetched {
+            return Ok(Deepened::Unreachable(e));
+        }
+
+        // The whole history is here already
+        if repo.shallow_commits()?.map(|commits| commits.to_vec()) == boundary {
+            break;
+        }
+
+        depth += step;
+        step *= 2;
+    }
+
+    Ok(match repo.has_object(id) {
+        true => Deepened::Found,
+        false => Deepened::NotFound,
+    })
+}
@@ -0,0 +1,89 @@
+//! Store clones only check out the files their mappings include, unless they are shared.
+//!
+//! The patterns are pathspecs matched exactly like `upload` matches them, which git's own
+//! sparse-checkout patterns are not, so they are kept in `.git/flippy_sparse` instead. Entries
+//! outside of them stay in the index with the skip-worktree flag.
+
+use anyhow::Result;
+use gix::{
+    Repository,
+    bstr::{BString, ByteSlice},
+    index::entry::Flags,
+    worktree::stack::state::attributes::Source,
+};
+
+const SPARSE_FILE: &str = "flippy_sparse";
+
+/// None when the whole worktree is checked out
+pub fn read(repo: &Repository) -> Result<Option<Vec<BString>>> {
+    match std::fs::read(repo.git_dir().join(SPARSE_FILE)) {
+        Ok(data) => Ok(Some(
+            data.lines()
+                .filter(|line| !line.is_empty())
+                .map(BString::from)
+                .collect(),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether the worktree was checked out with exactly these patterns, None being all of it
+pub fn is_current(repo: &Repository, patterns: Option<&[BString]>) -> Result<bool> {
+    Ok(read(repo)?.as_deref() == patterns)
+}
+
+/// Records the patterns for the next checkout, returns whether they changed
+pub fn write(repo: &Repository, patterns: Option<&[BString]>) -> Result<bool> {
+    if is_current(repo, patterns)? {
+        return Ok(false);
+    }
+
+    let Some(patterns) = patterns else {
+        std::fs::remove_file(repo.git_dir().join(SPARSE_FILE))?;
+        return Ok(true);
+    };
+
+    let mut data = BString::default();
+    for pattern in patterns {
+        data.extend_from_slice(pattern);
+        data.push(b'\n');
+    }
+
+    std::fs::write(repo.git_dir().join(SPARSE_FILE), data)?;
+
+    Ok(true)
+}
+
+/// Flags every entry outside of the recorded patterns as skip-worktree
+pub fn apply(repo: &Repository, index: &mut gix::index::File) -> Result<()> {
+    let Some(patterns) = read(repo)? else {
+        // Clears the flags of a clone that was sparse before
+        for entry in index.entries_mut() {
+            entry.flags.remove(Flags::SKIP_WORKTREE);
+        }
+        return Ok(());
+    };
+
+    let included = if patterns.is_empty() {
+        // An empty pathspec would match everything
+        vec![false; index.entries().len()]
+    } else {
+        let mut spec = repo.pathspec(false, &patterns, false, index, Source::IdMapping)?;
+
+        index
+            .entries()
+            .iter()
+            .map(|entry| spec.is_included(entry.path(index), Some(false)))
+            .collect()
+    };
+
+    for (entry, included) in index.entries_mut().iter_mut().zip(included) {
+        // Extended flags are only written, as an index V3, when EXTENDED is set
+        entry
+            .flags
+            .set(Flags::SKIP_WORKTREE | Flags::EXTENDED, !included);
+    }
+
+    Ok(())
+}
@@ -1,9 +1,13 @@
 use anyhow::{Result, anyhow};
 use clap::{ArgAction, Parser, Subcommand};
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
 use tokio::fs;
 use tracing::{Level, error, instrument};
-use types::flip::Flip;
+use types::{directory::Target, firmware::FirmwareSource, flip::Flip};
 
 use crate::art::{FLIPPY, get_art};
 
@@ -12,6 +16,8 @@ mod commands;
 mod flipper;
 mod git;
 mod progress;
+mod source;
+mod storage;
 mod types;
 mod validators;
 mod walking_diff;
@@ -47,15 +53,35 @@ enum Commands {
 
     /// Upload local changes to remote storage
     Upload {
-        /// Force walking full directory tree
+        /// Ignore the sync file and re-verify every mapping by walking the full directory tree
+        /// (size + MD5). Use this after editing the SD card by hand.
         #[arg(short, long)]
         force_walkdir: bool,
 
+        /// Write to a mounted flipper SD card at this path instead of over serial RPC
+        #[arg(short, long, value_parser)]
+        target: Option<PathBuf>,
+
+        /// Write every operation to a plan file for review instead of executing them
+        #[arg(long, value_parser)]
+        plan: Option<PathBuf>,
+
         /// Path of project
         #[arg(value_parser, default_value = ".")]
         path: PathBuf,
     },
 
+    /// Execute a plan made by `upload --plan`
+    Apply {
+        /// Plan file
+        #[arg(value_parser)]
+        plan: PathBuf,
+
+        /// Write to a mounted flipper SD card at this path instead of over serial RPC
+        #[arg(short, long, value_parser)]
+        target: Option<PathBuf>,
+    },
+
     /// Manages mappings in flip.toml files
     Map {
         /// Database type
@@ -77,7 +103,7 @@ enum Commands {
         path: PathBuf,
     },
 
-    /// Add or remove repositories
+    /// Add, remove or pin repositories
     Repo {
         #[command(subcommand)]
         command: RepoCommand,
@@ -100,13 +126,48 @@ enum Commands {
 enum RepoCommand {
     /// Add a repository to fetch files/folders from
     Add {
-        /// URL to repository
-        #[arg(value_parser)]
-        url: url::Url,
+        /// URL to repository, HTTP(S), SSH (`git@host:owner/repo.git`) or file. Also a folder
+        /// relative to the project, or a .zip/.tar.gz URL together with `--sha256`
+        url: String,
 
         /// Name for identification
         name: String,
 
+        /// Upload into a subdirectory of every database folder, e.g. /ext/subghz/<NAMESPACE>
+        #[arg(short, long)]
+        namespace: Option<String>,
+
+        /// Checksum of the archive, makes URL a .zip or .tar.gz archive instead of a git repository
+        #[arg(long)]
+        sha256: Option<String>,
+
+        /// Path of project
+        #[arg(value_parser, default_value = ".")]
+        path: PathBuf,
+    },
+
+    /// Pin a repository to a branch, tag or commit, uploads then never pick up anything else
+    Pin {
+        /// Repository name
+        name: String,
+
+        /// Branch, tag or commit
+        reference: String,
+
+        /// Kind of REFERENCE, detected from the store when omitted
+        #[arg(short, long, value_enum)]
+        kind: Option<commands::repo::PinKind>,
+
+        /// Path of project
+        #[arg(value_parser, default_value = ".")]
+        path: PathBuf,
+    },
+
+    /// Follow the remote's default branch again
+    Unpin {
+        /// Repository name
+        name: String,
+
         /// Path of project
         #[arg(value_parser, default_value = ".")]
         path: PathBuf,
@@ -137,27 +198,99 @@ enum FirmwareCommand {
 
     /// Pulls the current firmware into the store, then puts it onto the flipper and updates it.
     Update {
+        /// Hardware target to download for instead of asking the flipper, it is then only
+        /// installed when one is connected
+        #[arg(short, long)]
+        target: Option<Target>,
+
         /// Path of project
         #[arg(value_parser, default_value = ".")]
         path: PathBuf,
     },
+
+    /// Lists the channels and versions of published firmware
+    List {
+        /// official, momentum, unleashed or a source of the project, all of them when omitted
+        source: Option<String>,
+
+        /// Path of project, for its firmware sources
+        #[arg(long, value_parser, default_value = ".")]
+        path: PathBuf,
+    },
+
+    /// Prints the full changelog of a firmware version
+    Changelog {
+        /// `source@channel`, or `source@channel:version` for an older version
+        firmware: String,
+
+        /// Path of project, for its firmware sources
+        #[arg(value_parser, default_value = ".")]
+        path: PathBuf,
+    },
 }
 
 #[derive(Subcommand, Debug)]
 enum StoreCommand {
     /// Fetch all repositories and firmware files
     Fetch {
+        /// How many repositories to clone or fetch at once
+        #[arg(short, long, default_value = "4")]
+        jobs: NonZeroUsize,
+
         /// Path of project
         #[arg(value_parser, default_value = ".")]
         path: PathBuf,
     },
 
-    /// Deletes the store and everything inside of it
+    /// Deletes the store and everything inside of it, only what no project uses when shared
     Clean {
         /// Path of project
         #[arg(value_parser, default_value = ".")]
         path: PathBuf,
     },
+
+    /// Packs every repository and firmware package into a tar for machines without network
+    Export {
+        /// Tar to write
+        bundle: PathBuf,
+
+        /// Path of project
+        #[arg(value_parser, default_value = ".")]
+        path: PathBuf,
+    },
+
+    /// Restores a tar made by `store export` into the store
+    Import {
+        /// Tar to read
+        bundle: PathBuf,
+
+        /// Path of project
+        #[arg(value_parser, default_value = ".")]
+        path: PathBuf,
+    },
+
+    /// Lists what the store holds for every repository and firmware package
+    Status {
+        /// Path of project
+        #[arg(value_parser, default_value = ".")]
+        path: PathBuf,
+    },
+
+    /// Deletes store entries of removed repositories and old firmware packages
+    Gc {
+        /// How many of the most recently downloaded firmware packages to keep, besides the current
+        /// firmware and the ones a project references
+        #[arg(short, long, default_value = "2")]
+        keep: usize,
+
+        /// Also repack the objects of every clone, needs `git` installed
+        #[arg(long)]
+        repack: bool,
+
+        /// Path of project
+        #[arg(value_parser, default_value = ".")]
+        path: PathBuf,
+    },
 }
 
 #[tokio::main]
@@ -176,16 +309,23 @@ async fn main() -> Result<()> {
 }
 
 async fn run(cli: Cli) -> Result<()> {
+    let json = cli.json;
+
     match cli.command {
         Commands::New { path } => {
             commands::new::run(path).await?;
         }
         Commands::Upload {
             force_walkdir,
+            target,
+            plan,
             path,
         } => {
             let flip = try_flip_from_path(&path).await?;
-            commands::upload::run(flip, force_walkdir).await?;
+            commands::upload::run(flip, force_walkdir, target, plan).await?;
+        }
+        Commands::Apply { plan, target } => {
+            commands::apply::run(plan, target).await?;
         }
         Commands::Map {
             db_type,
@@ -198,9 +338,30 @@ async fn run(cli: Cli) -> Result<()> {
             commands::map::run(flip, db_type, repo, pathspec, excludes).await?;
         }
         Commands::Repo { command } => match command {
-            RepoCommand::Add { url, name, path } => {
+            RepoCommand::Add {
+                url,
+                name,
+                namespace,
+                sha256,
+                path,
+            } => {
                 let flip = try_flip_from_path(&path).await?;
-                commands::repo::add(flip, url, name).await?;
+                commands::repo::add(flip, url, name, namespace, sha256).await?;
+            }
+
+            RepoCommand::Pin {
+                name,
+                reference,
+                kind,
+                path,
+            } => {
+                let flip = try_flip_from_path(&path).await?;
+                commands::repo::pin(flip, name, reference, kind).await?;
+            }
+
+            RepoCommand::Unpin { name, path } => {
+                let flip = try_flip_from_path(&path).await?;
+                commands::repo::unpin(flip, name).await?;
             }
 
             RepoCommand::Remove { name, path } => {
@@ -215,29 +376,67 @@ async fn run(cli: Cli) -> Result<()> {
 
                 commands::firmware::set(flip, firmware).await?;
             }
-            FirmwareCommand::Update { path } => {
+            FirmwareCommand::Update { target, path } => {
                 let flip = try_flip_from_path(&path).await?;
 
-                commands::firmware::update(flip).await?;
+                commands::firmware::update(flip, target).await?;
+            }
+            FirmwareCommand::List { source, path } => {
+                let sources = firmware_sources(&path).await?;
+
+                commands::firmware::list(sources, source, json).await?;
+            }
+            FirmwareCommand::Changelog { firmware, path } => {
+                let sources = firmware_sources(&path).await?;
+
+                commands::firmware::changelog(sources, firmware.parse()?, json).await?;
             }
         },
 
         Commands::Store { command } => match command {
-            StoreCommand::Fetch { path } => {
+            StoreCommand::Fetch { jobs, path } => {
                 let flip = try_flip_from_path(&path).await?;
 
-                commands::store::fetch(flip).await?;
+                commands::store::fetch(flip, jobs).await?;
             }
             StoreCommand::Clean { path } => {
                 let flip = try_flip_from_path(&path).await?;
 
                 commands::store::clean(flip).await?;
             }
+            StoreCommand::Export { bundle, path } => {
+                let flip = try_flip_from_path(&path).await?;
+
+                commands::store::export(flip, bundle).await?;
+            }
+            StoreCommand::Import { bundle, path } => {
+                let flip = try_flip_from_path(&path).await?;
+
+                commands::store::import(flip, bundle).await?;
+            }
+            StoreCommand::Status { path } => {
+                let flip = try_flip_from_path(&path).await?;
+
+                commands::store::status(flip, json).await?;
+            }
+            StoreCommand::Gc { keep, repack, path } => {
+                let flip = try_flip_from_path(&path).await?;
+
+                commands::store::gc(flip, keep, repack).await?;
+            }
         },
     }
     Ok(())
 }
 
+/// `[firmware_sources]` of the project at `p`, none outside of a project
+async fn firmware_sources(p: impl AsRef<Path>) -> Result<HashMap<String, FirmwareSource>> {
+    match Flip::exists(&p).await? {
+        true => Ok(try_flip_from_path(p).await?.firmware_sources),
+        false => Ok(HashMap::new()),
+    }
+}
+
 async fn try_flip_from_path(p: impl AsRef<Path>) -> Result<Flip> {
     let project = fs::canonicalize(p).await?;
     if !Flip::exists(&project).await? {
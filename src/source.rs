@@ -0,0 +1,227 @@
+//! Files of repositories that are not git repositories: local folders and archives. Without
+//! commits to diff, they always go through `walking_diff`, and the sync file records a hash of
+//! their content instead of a commit.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use futures_util::StreamExt;
+use gix::{
+    bstr::BStr,
+    pathspec::{Defaults, Search},
+};
+use prodash::{Progress, tree::Item, unit};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt};
+use url::Url;
+
+use crate::types::mapping::MappingEntry;
+
+/// Checksum of the archive an extracted folder came from, lives inside of it
+pub const ARCHIVE_MARKER: &str = ".flippy_archive";
+
+#[derive(Debug, Clone, Copy)]
+pub enum ArchiveKind {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveKind {
+    pub fn from_url(url: &Url) -> Result<Self> {
+        let path = url.path();
+
+        if path.ends_with(".zip") {
+            Ok(Self::Zip)
+        } else if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            Ok(Self::TarGz)
+        } else {
+            bail!("unsupported archive `{url}`, expected a .zip, .tar.gz or .tgz")
+        }
+    }
+}
+
+/// Every file below `root` with its size, relative to `root` and sorted
+pub fn files(root: &Path) -> Result<Vec<(PathBuf, u32)>> {
+    let mut files = vec![];
+
+    let walk = walkdir::WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        // A local folder may well be a git checkout
+        .filter_entry(|entry| entry.file_name() != ".git");
+
+    for entry in walk {
+        let entry = entry?;
+
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let relative = entry.path().strip_prefix(root)?;
+
+        if relative == Path::new(ARCHIVE_MARKER) {
+            continue;
+        }
+
+        let size = u32::try_from(entry.metadata()?.len())
+            .with_context(|| format!("{} is too large for a flipper", relative.display()))?;
+
+        files.push((relative.to_path_buf(), size));
+    }
+
+    Ok(files)
+}
+
+/// Hash of every path and its content, in place of a commit hash in the sync file
+pub fn manifest(root: &Path, files: &[(PathBuf, u32)]) -> Result<[u8; 20]> {
+    let mut hasher = Sha256::new();
+
+    for (path, size) in files {
+        hasher.update(path.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(size.to_le_bytes());
+        hasher.update(std::fs::read(root.join(path))?);
+    }
+
+    Ok(hasher.finalize()[..20].try_into()?)
+}
+
+/// Files a mapping includes, relative to the longest common directory of its patterns, which is
+/// returned as well. Matches exactly like the pathspecs of git repositories.
+pub fn select(
+    files: &[(PathBuf, u32)],
+    entry: &MappingEntry,
+) -> Result<(PathBuf, Vec<(PathBuf, u32)>)> {
+    let (lcd, selected) = matching(files, entry)?;
+
+    if selected.is_empty() {
+        bail!("No files to change. You may ignore this if your patterns did not match anything");
+    }
+
+    Ok((lcd, selected))
+}
+
+/// Like [`select`], but nothing matching is not an error
+pub fn matching(
+    files: &[(PathBuf, u32)],
+    entry: &MappingEntry,
+) -> Result<(PathBuf, Vec<(PathBuf, u32)>)> {
+    let patterns = entry
+        .patterns()
+        .map(|pattern| gix::pathspec::parse(&pattern, Defaults::default()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut search = Search::from_specs(patterns, None, Path::new(""))?;
+
+    let lcd = search
+        .longest_common_directory()
+        .map(|lcd| lcd.into_owned())
+        .unwrap_or_default();
+
+    let mut selected = vec![];
+
+    for (path, size) in files {
+        let relative = gix::path::to_unix_separators_on_windows(gix::path::into_bstr(path));
+        let relative: &BStr = relative.as_ref();
+
+        let included = search
+            .pattern_matching_relative_path(relative, Some(false), &mut |_, _, _, _| false)
+            .is_some_and(|m| !m.is_excluded());
+
+        if included {
+            selected.push((path.strip_prefix(&lcd)?.to_path_buf(), *size));
+        }
+    }
+
+    Ok((lcd, selected))
+}
+
+/// Downloads and extracts `url` into `dest` unless it already holds exactly that archive.
+/// Returns whether it was extracted.
+pub async fn fetch_archive(
+    url: &Url,
+    sha256: &str,
+    dest: &Path,
+    progress: &mut Item,
+) -> Result<bool> {
+    let marker = dest.join(ARCHIVE_MARKER);
+
+    if fs::read_to_string(&marker).await.ok().as_deref() == Some(sha256) {
+        return Ok(false);
+    }
+
+    let kind = ArchiveKind::from_url(url)?;
+    let download = dest.with_extension("download");
+    let extracted = dest.with_extension("extracting");
+
+    let response = reqwest::get(url.as_str()).await?.error_for_status()?;
+
+    let item = progress.add_child("downloading");
+    item.init(
+        response.content_length().map(|length| length as usize),
+        Some(unit::dynamic_and_mode(
+            unit::Bytes,
+            unit::display::Mode::with_throughput(),
+        )),
+    );
+
+    let mut file = fs::File::create(&download).await?;
+    let mut hasher = Sha256::new();
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        item.inc_by(chunk.len());
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+
+    file.flush().await?;
+    drop(file);
+
+    let actual = hex::encode(hasher.finalize());
+    if !actual.eq_ignore_ascii_case(sha256) {
+        fs::remove_file(&download).await?;
+        bail!("hash mismatch, expected {sha256}, got {actual}");
+    }
+
+    item.done(format!("downloaded, sha256 {actual}"));
+    progress.info("extracting".to_string());
+
+    if fs::try_exists(&extracted).await? {
+        fs::remove_dir_all(&extracted).await?;
+    }
+
+    {
+        let download = download.clone();
+        let extracted = extracted.clone();
+
+        tokio::task::spawn_blocking(move || extract(kind, &download, &extracted)).await??;
+    }
+
+    fs::write(extracted.join(ARCHIVE_MARKER), sha256).await?;
+    fs::remove_file(&download).await?;
+
+    // Swapped in only once complete, an interrupted download leaves the old files in place
+    if fs::try_exists(dest).await? {
+        fs::remove_dir_all(dest).await?;
+    }
+    fs::rename(&extracted, dest).await?;
+
+    Ok(true)
+}
+
+/// Both refuse entries that would end up outside of `dest`
+fn extract(kind: ArchiveKind, archive: &Path, dest: &Path) -> Result<()> {
+    let file = std::fs::File::open(archive)?;
+
+    match kind {
+        ArchiveKind::Zip => zip::ZipArchive::new(file)?.extract(dest)?,
+        ArchiveKind::TarGz => {
+            std::fs::create_dir_all(dest)?;
+            tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(dest)?
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! Storage backends that `upload` can write to. Either the flipper itself over serial RPC, or its
+//! SD card mounted on the host, which is a LOT faster for large databases.
+
+use std::{
+    borrow::Cow,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::mpsc::Sender,
+};
+
+use flipper_rpc::{
+    error::{Error, Result},
+    fs::{
+        EXTERNAL_STORAGE, FsCreateDir, FsRead, FsReadDir, FsRemove, FsWrite, helpers::os_str_to_str,
+    },
+    rpc::{self, req::Request, res::ReadDirItem},
+    transport::{Transport, serial::rpc::SerialRpcTransport},
+};
+
+/// Every filesystem operation needed to diff and upload onto a flipper.
+pub trait Storage: FsRead + FsReadDir + FsWrite + FsCreateDir + FsRemove + FsRename {}
+impl<T> Storage for T where T: FsRead + FsReadDir + FsWrite + FsCreateDir + FsRemove + FsRename {}
+
+/// flipper-rpc has the storage rename request, but no filesystem trait for it
+pub trait FsRename {
+    /// Moves a file or directory, the parent of `to` must exist
+    fn fs_rename(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()>;
+}
+
+impl FsRename for SerialRpcTransport {
+    fn fs_rename(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+        let from = os_str_to_str(from.as_ref().as_os_str())?.to_string();
+        let to = os_str_to_str(to.as_ref().as_os_str())?.to_string();
+
+        self.send_and_receive(Request::StorageRename(from, to))?;
+
+        Ok(())
+    }
+}
+
+/// A flipper SD card mounted on the host filesystem. Paths are given as they are on the flipper,
+/// (`/ext/...`) and are translated onto the mount point.
+#[derive(Debug)]
+pub struct SdCard {
+    mount: PathBuf,
+}
+
+impl SdCard {
+    pub fn new(mount: impl Into<PathBuf>) -> Self {
+        Self {
+            mount: mount.into(),
+        }
+    }
+
+    /// Translates `/ext/a/b` into `<mount>/a/b`
+    fn resolve(&self, path: impl AsRef<Path>) -> Result<PathBuf> {
+        let path = path.as_ref();
+        let relative = path.strip_prefix(EXTERNAL_STORAGE).map_err(|_| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{path:?} is not on the external storage ({EXTERNAL_STORAGE})"),
+            )
+        })?;
+
+        // Do not let `..` escape the mount point
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{path:?} is not a normalized path"),
+            )
+            .into());
+        }
+
+        Ok(self.mount.join(relative))
+    }
+}
+
+/// Maps IO errors onto the storage errors the flipper would have sent for the same operation, so
+/// callers can match on them regardless of the backend.
+fn storage_error(err: std::io::Error) -> Error {
+    let storage = match err.kind() {
+        ErrorKind::NotFound => rpc::error::StorageError::NotFound,
+        ErrorKind::AlreadyExists => rpc::error::StorageError::AlreadyExists,
+        ErrorKind::PermissionDenied => rpc::error::StorageError::PermissionDenied,
+        ErrorKind::DirectoryNotEmpty => rpc::error::StorageError::DirectoryNotEmpty,
+        _ => return err.into(),
+    };
+
+    rpc::error::Error::StorageError(storage).into()
+}
+
+impl FsRead for SdCard {
+    fn fs_read(&mut self, path: impl AsRef<Path>) -> Result<Cow<'static, [u8]>> {
+        let path = self.resolve(path)?;
+
+        Ok(Cow::Owned(std::fs::read(path).map_err(storage_error)?))
+    }
+}
+
+impl FsReadDir for SdCard {
+    fn fs_read_dir(
+        &mut self,
+        path: impl AsRef<Path>,
+        include_md5: bool,
+    ) -> Result<impl Iterator<Item = ReadDirItem>> {
+        let path = self.resolve(path)?;
+
+        let mut items = Vec::new();
+
+        for entry in std::fs::read_dir(&path).map_err(storage_error)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+
+            if entry.file_type()?.is_dir() {
+                items.push(ReadDirItem::Dir(name));
+            } else {
+                let size = entry.metadata()?.len() as u32;
+                let md5 = if include_md5 {
+                    let data = std::fs::read(entry.path())?;
+                    Some(hex::encode(*md5::compute(data)))
+                } else {
+                    None
+                };
+
+                items.push(ReadDirItem::File(name, size, md5));
+            }
+        }
+
+        Ok(items.into_iter())
+    }
+}
+
+impl FsWrite for SdCard {
+    fn fs_write(
+        &mut self,
+        path: impl AsRef<Path>,
+        data: impl AsRef<[u8]>,
+        tx: Option<Sender<usize>>,
+    ) -> Result<()> {
+        let path = self.resolve(path)?;
+        let data = data.as_ref();
+
+        if let Some(ref tx) = tx {
+            tx.send(0)?;
+        }
+
+        std::fs::write(path, data).map_err(storage_error)?;
+
+        if let Some(ref tx) = tx {
+            tx.send(data.len())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FsCreateDir for SdCard {
+    fn fs_create_dir(&mut self, path: impl AsRef<Path>) -> Result<bool> {
+        let path = self.resolve(path)?;
+
+        match std::fs::create_dir(path) {
+            Ok(()) => Ok(false),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(true),
+            Err(e) => Err(storage_error(e)),
+        }
+    }
+}
+
+impl FsRemove for SdCard {
+    fn fs_remove(&mut self, path: impl AsRef<Path>, recursive: bool) -> Result<()> {
+        let path = self.resolve(path)?;
+
+        let metadata = std::fs::symlink_metadata(&path).map_err(storage_error)?;
+
+        if !metadata.is_dir() {
+            std::fs::remove_file(path)
+        } else if recursive {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_dir(path)
+        }
+        .map_err(storage_error)
+    }
+}
+
+impl FsRename for SdCard {
+    fn fs_rename(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+        let from = self.resolve(from)?;
+        let to = self.resolve(to)?;
+
+        // The flipper refuses to overwrite, std::fs::rename would not
+        if std::fs::exists(&to)? {
+            return Err(
+                rpc::error::Error::StorageError(rpc::error::StorageError::AlreadyExists).into(),
+            );
+        }
+
+        std::fs::rename(from, to).map_err(storage_error)
+    }
+}
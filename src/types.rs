@@ -1,6 +1,9 @@
 pub mod directory;
 pub mod firmware;
 pub mod flip;
+pub mod managed_files;
 pub mod mapping;
+pub mod references;
+pub mod releases;
 pub mod remote_sync_file;
 pub mod repository;
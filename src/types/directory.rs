@@ -3,7 +3,7 @@ use std::{fmt::Display, path::Path};
 use crate::progress::progress;
 use anyhow::{Context, Result, bail};
 use reqwest::header::CONTENT_LENGTH;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tokio::io::{AsyncWriteExt, BufWriter};
 use url::Url;
@@ -20,11 +20,7 @@ pub struct Directory {
 #[derive(Deserialize, Debug, Clone)]
 pub struct Channel {
     pub id: Id,
-
-    #[allow(dead_code)]
     pub title: String,
-
-    #[allow(dead_code)]
     pub description: String,
 
     pub versions: Option<Vec<Version>>,
@@ -38,11 +34,34 @@ pub struct Version {
 }
 
 impl Version {
-    pub fn latest_tgz(&self) -> Result<&File> {
-        self.files
+    /// The `update_tgz` built for `target`, or the one for any target
+    pub fn update_tgz(&self, target: Target) -> Result<&File> {
+        let packages: Vec<&File> = self
+            .files
+            .iter()
+            .filter(|f| f.file_type == "update_tgz")
+            .collect();
+
+        let found = packages
             .iter()
-            .find(|f| f.file_type == "update_tgz")
-            .with_context(|| format!("no `update_tgz` file found in version {}", self.version))
+            .find(|f| f.target == target)
+            .or_else(|| packages.iter().find(|f| f.target == Target::Any));
+
+        match found {
+            Some(file) => Ok(file),
+            None if packages.is_empty() => {
+                bail!("no `update_tgz` file found in version {}", self.version)
+            }
+            None => bail!(
+                "no `update_tgz` for {target} in version {}, only for {}",
+                self.version,
+                packages
+                    .iter()
+                    .map(|f| f.target.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
     }
 }
 
@@ -84,7 +103,13 @@ pub struct File {
 
     #[serde(alias = "type")]
     pub file_type: String,
-    pub sha256: String,
+
+    /// Always given by a `directory.json`, from [`File::checksum`] for releases
+    pub sha256: Option<String>,
+
+    /// Release asset holding the sha256, see [`super::releases::fetch_checksums`]
+    #[serde(skip)]
+    pub checksum: Option<Url>,
 }
 
 impl File {
@@ -151,7 +176,11 @@ impl Display for File {
         writeln!(f, "Type: {}", self.file_type)?;
         writeln!(f, "Target: {}", self.target)?;
         writeln!(f, "URL: {}", self.url)?;
-        writeln!(f, "Sha256: {}", self.sha256)?;
+        writeln!(
+            f,
+            "Sha256: {}",
+            self.sha256.as_deref().unwrap_or("not published")
+        )?;
         Ok(())
     }
 }
@@ -167,7 +196,7 @@ impl Display for Target {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum Id {
     #[serde(rename = "development")]
     Development,
@@ -189,34 +218,169 @@ impl Display for Id {
     }
 }
 
-#[derive(Deserialize, Debug, Clone, Copy)]
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum,
+)]
 pub enum Target {
     #[serde(rename = "f7")]
     F7,
     #[serde(rename = "f18")]
     F18,
+
+    /// Only ever a package, never a device
     #[serde(rename = "any")]
+    #[value(skip)]
     Any,
 }
 
+impl Target {
+    /// Whether a package built for `self` runs on a `device`
+    pub fn runs_on(self, device: Target) -> bool {
+        self == Target::Any || self == device
+    }
+}
+
 impl Directory {
     pub async fn fetch(url: Url) -> Result<Self> {
         let response = reqwest::get(url).await?.json().await?;
         Ok(response)
     }
 
-    pub fn channel_latest_version(&self, channel: &Id) -> Option<&Version> {
-        let channel = self.channels.iter().find(|&x| x.id.eq(channel))?;
+    /// `version` of `channel`, or its newest version by release time when None
+    pub fn channel_version(&self, channel: &Id, version: Option<&str>) -> Result<&Version> {
+        let Some(found) = self.channels.iter().find(|x| x.id == *channel) else {
+            bail!("the firmware has no `{channel}` channel");
+        };
 
-        let versions = &channel
-            .versions
-            .as_ref()
-            .expect("No versions available for the selected channel");
+        let versions = found.versions.as_deref().unwrap_or_default();
+
+        let Some(newest) = versions.iter().max_by_key(|v| v.timestamp) else {
+            bail!("no versions available for channel `{channel}`");
+        };
+
+        let Some(version) = version else {
+            return Ok(newest);
+        };
+
+        match versions.iter().find(|v| v.version == version) {
+            Some(version) => Ok(version),
+            None => {
+                let mut available: Vec<&Version> = versions.iter().collect();
+                available.sort_by_key(|v| std::cmp::Reverse(v.timestamp));
+
+                let available: Vec<&str> = available.iter().map(|v| v.version.as_str()).collect();
+
+                bail!(
+                    "version `{version}` is not in channel `{channel}`, available: {}",
+                    available.join(", ")
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(target: Target) -> File {
+        File {
+            url: Url::parse(&format!("https://example.com/{target}.tgz")).unwrap(),
+            target,
+            file_type: "update_tgz".to_string(),
+            sha256: None,
+            checksum: None,
+        }
+    }
+
+    fn version(version: &str, timestamp: u64, files: Vec<File>) -> Version {
+        Version {
+            version: version.to_string(),
+            changelog: String::new(),
+            timestamp,
+            files,
+        }
+    }
+
+    /// Listed out of order, the newest is neither first nor last
+    fn directory() -> Directory {
+        Directory {
+            channels: vec![Channel {
+                id: Id::Release,
+                title: "Release".to_string(),
+                description: String::new(),
+                versions: Some(vec![
+                    version("1.1.0", 2000, vec![]),
+                    version("1.2.0", 3000, vec![]),
+                    version("1.0.0", 1000, vec![]),
+                ]),
+            }],
+        }
+    }
+
+    #[test]
+    fn newest_by_timestamp() {
+        let directory = directory();
+
+        assert_eq!(
+            directory
+                .channel_version(&Id::Release, None)
+                .unwrap()
+                .version,
+            "1.2.0"
+        );
+    }
+
+    #[test]
+    fn pinned_version() {
+        let directory = directory();
+
+        assert_eq!(
+            directory
+                .channel_version(&Id::Release, Some("1.0.0"))
+                .unwrap()
+                .version,
+            "1.0.0"
+        );
+    }
+
+    #[test]
+    fn unknown_version_lists_available() {
+        let directory = directory();
+        let error = directory
+            .channel_version(&Id::Release, Some("9.9.9"))
+            .unwrap_err()
+            .to_string();
+
+        assert!(error.contains("1.2.0, 1.1.0, 1.0.0"), "{error}");
+    }
+
+    #[test]
+    fn missing_channel() {
+        assert!(directory().channel_version(&Id::Development, None).is_err());
+    }
+
+    #[test]
+    fn update_tgz_for_target() {
+        let both = version("1.2.0", 0, vec![file(Target::F18), file(Target::F7)]);
+
+        assert_eq!(both.update_tgz(Target::F7).unwrap().target, Target::F7);
+        assert_eq!(both.update_tgz(Target::F18).unwrap().target, Target::F18);
+    }
+
+    #[test]
+    fn update_tgz_falls_back_to_any() {
+        let any = version("1.2.0", 0, vec![file(Target::Any), file(Target::F18)]);
+
+        assert_eq!(any.update_tgz(Target::F7).unwrap().target, Target::Any);
+        assert_eq!(any.update_tgz(Target::F18).unwrap().target, Target::F18);
+    }
 
-        // At the moment, i have not seen a channel with multiple versions. The
-        // latest version should be first if it is sorted properly anyways.
-        let version = &versions[0];
+    #[test]
+    fn update_tgz_missing_target() {
+        let f18 = version("1.2.0", 0, vec![file(Target::F18)]);
 
-        Some(version)
+        assert!(f18.update_tgz(Target::F7).is_err());
+        assert!(version("1.2.0", 0, vec![]).update_tgz(Target::F7).is_err());
     }
 }
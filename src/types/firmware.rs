@@ -1,24 +1,179 @@
+use std::{collections::HashMap, path::Path, str::FromStr};
+
 use anyhow::{Context, Result, bail};
-use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, IntoDeserializer},
+};
 use url::Url;
 
-use super::directory::{
-    Directory, Id, MOMENTUM_DIRECTORY, OFFICIAL_DIRECTORY, UNLEASHED_DIRECTORY, Version,
+use super::{
+    directory::{
+        Directory, Id, MOMENTUM_DIRECTORY, OFFICIAL_DIRECTORY, Target, UNLEASHED_DIRECTORY, Version,
+    },
+    releases,
 };
 
-#[derive(Debug)]
+/// Published firmware is a channel with an optional version, written as `official@release` or
+/// `official@release:1.2.0`. Without a version the newest release of the channel is used.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Firmware {
-    Official(Id),
-    Momentum(Id),
-    Unleashed(Id),
-    // TODO: Rougemaster, the API is not standard
-    // RougeMaster,
+    Official(Id, Option<String>),
+    Momentum(Id, Option<String>),
+    Unleashed(Id, Option<String>),
+
+    /// A source from `[firmware_sources]` in `flip.toml`, by its name
+    Named(String, Id, Option<String>),
     Custom(String),
 }
 
+/// Every published firmware and its `directory.json`, by the name used in `source@channel`
+pub const SOURCES: [(&str, &str); 3] = [
+    ("official", OFFICIAL_DIRECTORY),
+    ("momentum", MOMENTUM_DIRECTORY),
+    ("unleashed", UNLEASHED_DIRECTORY),
+];
+
+/// A firmware source of `flip.toml`, `[firmware_sources.<name>]`. Either a `directory.json`, or
+/// the releases of a fork that never publishes one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FirmwareSource {
+    /// `directory.json` following the same spec as the published firmware
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<Url>,
+
+    /// GitHub-style releases API, `https://api.github.com/repos/<owner>/<repo>/releases`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub releases: Option<Url>,
+
+    /// Pattern of the `.tgz` among the assets of a release, `*.tgz` when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset: Option<String>,
+
+    /// Pattern of the asset holding the sha256 of the `.tgz`, unverified when not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+/// Where a [`FirmwareSource`] gets its versions from
+pub enum SourceKind<'a> {
+    Directory(&'a Url),
+    Releases {
+        url: &'a Url,
+        asset: &'a str,
+        checksum: Option<&'a str>,
+    },
+}
+
+impl FirmwareSource {
+    pub fn from_directory(directory: &str) -> Result<Self> {
+        Ok(Self {
+            directory: Some(Url::parse(directory).context("parsing base directory URL")?),
+            ..Default::default()
+        })
+    }
+
+    /// Exactly one of `directory` or `releases`, `asset` and `checksum` only for releases
+    pub fn kind(&self) -> Result<SourceKind<'_>> {
+        match (&self.directory, &self.releases) {
+            (Some(_), Some(_)) => bail!("only one of `directory` or `releases` can be set"),
+            (None, None) => bail!("either `directory` or `releases` has to be set"),
+            (Some(directory), None) => {
+                if self.asset.is_some() || self.checksum.is_some() {
+                    bail!("`asset` and `checksum` only apply to `releases`");
+                }
+
+                Ok(SourceKind::Directory(directory))
+            }
+            (None, Some(url)) => Ok(SourceKind::Releases {
+                url,
+                asset: self.asset.as_deref().unwrap_or("*.tgz"),
+                checksum: self.checksum.as_deref(),
+            }),
+        }
+    }
+
+    /// The `directory.json` or releases API
+    pub fn url(&self) -> Result<&Url> {
+        match self.kind()? {
+            SourceKind::Directory(url) | SourceKind::Releases { url, .. } => Ok(url),
+        }
+    }
+
+    /// Every channel and version, releases are mapped onto the `directory.json` layout
+    pub async fn fetch(&self) -> Result<Directory> {
+        match self.kind()? {
+            SourceKind::Directory(url) => Directory::fetch(url.clone()).await,
+            SourceKind::Releases {
+                url,
+                asset,
+                checksum,
+            } => releases::fetch(url, asset, checksum).await,
+        }
+    }
+}
+
+/// Whether `name` can be used as the source in `source@channel`
+pub fn is_source_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Written next to every package `firmware update` downloads into the store
+pub const PACKAGE_FILE: &str = "package.json";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Package {
+    pub firmware: Firmware,
+
+    /// Where the `.tgz` was downloaded from, its UUID in the store is derived from it
+    pub url: Url,
+
+    /// None for custom firmware
+    pub version: Option<String>,
+
+    /// None for custom firmware, and packages downloaded before this was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<Target>,
+
+    /// Name of the `.tgz` in the same folder
+    pub file: String,
+
+    /// Checksum the download was verified against, None for custom firmware
+    pub sha256: Option<String>,
+}
+
+impl Package {
+    /// None for packages downloaded before this was recorded
+    pub async fn load(dir: &Path) -> Result<Option<Self>> {
+        match tokio::fs::read(dir.join(PACKAGE_FILE)).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn save(&self, dir: &Path) -> Result<()> {
+        tokio::fs::write(dir.join(PACKAGE_FILE), serde_json::to_vec_pretty(self)?).await?;
+
+        Ok(())
+    }
+
+    /// Whether this package is `firmware`. A pinned version only accepts that version, whatever
+    /// was pinned when it was downloaded.
+    pub fn is_for(&self, firmware: &Firmware) -> bool {
+        self.firmware.unpinned() == firmware.unpinned()
+            && firmware
+                .version()
+                .is_none_or(|pinned| self.version.as_deref() == Some(pinned))
+    }
+}
+
 impl Default for Firmware {
     fn default() -> Self {
-        Self::Official(Id::Release)
+        Self::Official(Id::Release, None)
     }
 }
 
@@ -27,15 +182,19 @@ impl Serialize for Firmware {
     where
         S: Serializer,
     {
-        let s = match self {
-            Firmware::Official(channel) => &format!("official@{channel}"),
-            Firmware::Momentum(channel) => &format!("momentum@{channel}"),
-            Firmware::Unleashed(channel) => &format!("unleashed@{channel}"),
+        let (source, channel, version) = match self {
+            Firmware::Official(channel, version) => ("official", channel, version),
+            Firmware::Momentum(channel, version) => ("momentum", channel, version),
+            Firmware::Unleashed(channel, version) => ("unleashed", channel, version),
+            Firmware::Named(source, channel, version) => (source.as_str(), channel, version),
 
-            // Firmware::RougeMaster => "rougemaster",
-            Firmware::Custom(url) => url,
+            Firmware::Custom(url) => return serializer.serialize_str(url),
         };
-        serializer.serialize_str(s)
+
+        match version {
+            Some(version) => serializer.serialize_str(&format!("{source}@{channel}:{version}")),
+            None => serializer.serialize_str(&format!("{source}@{channel}")),
+        }
     }
 }
 
@@ -46,19 +205,34 @@ impl<'de> Deserialize<'de> for Firmware {
     {
         let firmware = String::deserialize(deserializer)?;
 
-        if let Some((source, channel)) = firmware.split_once('@') {
+        // `user@host` of a URL is not a source
+        let published = firmware
+            .split_once('@')
+            .filter(|(source, _)| is_source_name(source));
+
+        if let Some((source, channel)) = published {
+            let (channel, version) = match channel.split_once(':') {
+                Some((_, "")) => return Err(de::Error::custom("empty firmware version after `:`")),
+                Some((channel, version)) => (channel, Some(version.to_string())),
+                None => (channel, None),
+            };
+
             let channel = match channel {
                 "release" => Id::Release,
                 "release-candidate" => Id::ReleaseCanidate,
                 "development" => Id::Development,
-                _ => todo!(),
+                _ => {
+                    return Err(de::Error::custom(format!(
+                        "unknown channel `{channel}`, expected release, release-candidate or development"
+                    )));
+                }
             };
 
             match source {
-                "official" => Ok(Firmware::Official(channel)),
-                "momentum" => Ok(Firmware::Momentum(channel)),
-                "unleashed" => Ok(Firmware::Unleashed(channel)),
-                _ => todo!(),
+                "official" => Ok(Firmware::Official(channel, version)),
+                "momentum" => Ok(Firmware::Momentum(channel, version)),
+                "unleashed" => Ok(Firmware::Unleashed(channel, version)),
+                _ => Ok(Firmware::Named(source.to_string(), channel, version)),
             }
         } else {
             let url = Url::parse(&firmware)
@@ -68,32 +242,195 @@ impl<'de> Deserialize<'de> for Firmware {
     }
 }
 
+impl FromStr for Firmware {
+    type Err = de::value::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::deserialize(s.into_deserializer())
+    }
+}
+
 impl Firmware {
-    pub async fn fetch_manifest(&self) -> Result<Version> {
+    /// `sources` are the `[firmware_sources]` of `flip.toml`. Checksums kept in a separate release
+    /// asset are not downloaded, see [`releases::fetch_checksums`].
+    pub async fn fetch_manifest(
+        &self,
+        sources: &HashMap<String, FirmwareSource>,
+    ) -> Result<Version> {
         match self {
             Firmware::Custom(_) => bail!("fetch_manifest called on a custom firmware variant"),
 
             // All published variants follow the same directory.json spec
-            Firmware::Official(ch) | Firmware::Unleashed(ch) | Firmware::Momentum(ch) => {
-                let base =
-                    Url::parse(self.get_directory()).context("parsing base directory URL")?;
-                let dir = Directory::fetch(base).await?;
-                let ver = dir
-                    .channel_latest_version(ch)
-                    .cloned()
-                    .with_context(|| format!("no latest version for channel `{}`", ch))?;
-
-                Ok(ver)
+            Firmware::Official(ch, version)
+            | Firmware::Unleashed(ch, version)
+            | Firmware::Momentum(ch, version)
+            | Firmware::Named(_, ch, version) => {
+                let dir = self.source(sources)?.fetch().await?;
+
+                Ok(dir.channel_version(ch, version.as_deref())?.clone())
             }
         }
     }
 
-    fn get_directory(&self) -> &'static str {
+    /// The pinned version, None when following the newest release or for custom firmware
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            Firmware::Official(_, version)
+            | Firmware::Momentum(_, version)
+            | Firmware::Unleashed(_, version)
+            | Firmware::Named(_, _, version) => version.as_deref(),
+            Firmware::Custom(_) => None,
+        }
+    }
+
+    /// The same source and channel, following the newest release
+    pub fn unpinned(&self) -> Firmware {
         match self {
-            Firmware::Official(_) => OFFICIAL_DIRECTORY,
-            Firmware::Unleashed(_) => UNLEASHED_DIRECTORY,
-            Firmware::Momentum(_) => MOMENTUM_DIRECTORY,
-            Firmware::Custom(_) => unreachable!("matched in fetch_manifest, error taken care of."),
+            Firmware::Official(ch, _) => Firmware::Official(*ch, None),
+            Firmware::Momentum(ch, _) => Firmware::Momentum(*ch, None),
+            Firmware::Unleashed(ch, _) => Firmware::Unleashed(*ch, None),
+            Firmware::Named(source, ch, _) => Firmware::Named(source.clone(), *ch, None),
+            Firmware::Custom(url) => Firmware::Custom(url.clone()),
+        }
+    }
+
+    /// Where the versions come from, named sources are looked up in `sources`
+    pub fn source(&self, sources: &HashMap<String, FirmwareSource>) -> Result<FirmwareSource> {
+        let directory = match self {
+            Firmware::Official(..) => OFFICIAL_DIRECTORY,
+            Firmware::Unleashed(..) => UNLEASHED_DIRECTORY,
+            Firmware::Momentum(..) => MOMENTUM_DIRECTORY,
+            Firmware::Named(source, ..) => match sources.get(source) {
+                Some(found) => return Ok(found.clone()),
+                None => bail!(
+                    "unknown firmware `{source}`, expected official, momentum, unleashed or a \
+                     source in [firmware_sources] of flip.toml"
+                ),
+            },
+            Firmware::Custom(_) => bail!("custom firmware has no directory"),
+        };
+
+        FirmwareSource::from_directory(directory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_round_trip() {
+        for text in [
+            "lab@development",
+            "lab@release:1.2.0",
+            "official@release:1.2.0",
+        ] {
+            let firmware: Firmware = text.parse().unwrap();
+            assert_eq!(
+                serde_json::to_string(&firmware).unwrap(),
+                format!("\"{text}\"")
+            );
         }
+
+        assert_eq!(
+            "lab@release-candidate:0.9".parse::<Firmware>().unwrap(),
+            Firmware::Named(
+                "lab".to_string(),
+                Id::ReleaseCanidate,
+                Some("0.9".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn url_with_user_stays_custom() {
+        let url = "https://user@example.com/fw.tgz";
+
+        assert_eq!(
+            url.parse::<Firmware>().unwrap(),
+            Firmware::Custom(url.to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_channel_and_version() {
+        assert!("lab@nightly".parse::<Firmware>().is_err());
+        assert!("lab@release:".parse::<Firmware>().is_err());
+    }
+
+    fn source(directory: bool, releases: bool, asset: bool) -> FirmwareSource {
+        let url = Url::parse("http://127.0.0.1/directory.json").unwrap();
+
+        FirmwareSource {
+            directory: directory.then(|| url.clone()),
+            releases: releases.then(|| url.clone()),
+            asset: asset.then(|| "*.tgz".to_string()),
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn source_kind() {
+        assert!(matches!(
+            source(true, false, false).kind(),
+            Ok(SourceKind::Directory(_))
+        ));
+        assert!(matches!(
+            source(false, true, true).kind(),
+            Ok(SourceKind::Releases { .. })
+        ));
+
+        assert!(source(true, true, false).kind().is_err());
+        assert!(source(false, false, false).kind().is_err());
+        assert!(source(true, false, true).kind().is_err());
+    }
+
+    /// Serves `body` once on a random local port
+    async fn serve(body: &'static str) -> Url {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/directory.json", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        Url::parse(&url).unwrap()
+    }
+
+    const DIRECTORY: &str = r#"{"channels":[{"id":"release","title":"Release","description":"","versions":[
+        {"version":"1.0.0","changelog":"","timestamp":1000,"files":[]},
+        {"version":"1.1.0","changelog":"","timestamp":2000,"files":[]}]}]}"#;
+
+    #[tokio::test]
+    async fn named_source_from_local_directory() {
+        let sources = HashMap::from([(
+            "lab".to_string(),
+            FirmwareSource {
+                directory: Some(serve(DIRECTORY).await),
+                ..Default::default()
+            },
+        )]);
+
+        let firmware: Firmware = "lab@release".parse().unwrap();
+        let version = firmware.fetch_manifest(&sources).await.unwrap();
+
+        assert_eq!(version.version, "1.1.0");
+    }
+
+    #[tokio::test]
+    async fn unknown_named_source() {
+        let firmware: Firmware = "lab@release".parse().unwrap();
+
+        assert!(firmware.fetch_manifest(&HashMap::new()).await.is_err());
     }
 }
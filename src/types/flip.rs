@@ -3,7 +3,13 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use super::{firmware::Firmware, repository::Repository};
+use super::{
+    firmware::{Firmware, FirmwareSource, SOURCES, is_source_name},
+    repository::Repository,
+};
+use crate::validators::validate_namespace;
+use anyhow::{Context, bail};
+use gix::bstr::BString;
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 use tracing::{debug, trace};
@@ -16,6 +22,16 @@ pub struct Flip {
     pub name: String,
     // pub path: PathBuf,
     pub firmware: Firmware,
+
+    /// Keep clones and firmware in the cache directory shared by every project, instead of in
+    /// `store/` next to `flip.toml`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shared_store: bool,
+
+    /// Firmware publishing a `directory.json`, usable as `<name>@<channel>`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub firmware_sources: HashMap<String, FirmwareSource>,
+
     pub repositories: HashMap<String, Repository>,
 }
 
@@ -36,9 +52,70 @@ impl Flip {
 
         flip.source_path = path.to_path_buf();
 
+        flip.validate_firmware()?;
+
+        for (name, repo) in &flip.repositories {
+            repo.validate_source()
+                .with_context(|| format!("invalid source of repository `{name}`"))?;
+            repo.pin()
+                .with_context(|| format!("invalid pin in repository `{name}`"))?;
+            repo.validate_auth()
+                .with_context(|| format!("invalid auth in repository `{name}`"))?;
+
+            let namespaces = repo.namespace.iter().chain(
+                repo.mappings
+                    .iter()
+                    .filter_map(|m| m.entry().namespace.as_ref()),
+            );
+
+            for namespace in namespaces {
+                validate_namespace(namespace)
+                    .with_context(|| format!("invalid namespace in repository `{name}`"))?;
+            }
+        }
+
         Ok(flip)
     }
 
+    /// Named firmware sources need a usable name, and the firmware must be one of them when named
+    pub fn validate_firmware(&self) -> anyhow::Result<()> {
+        for (name, source) in &self.firmware_sources {
+            if !is_source_name(name) {
+                bail!(
+                    "invalid firmware source name `{name}`, only letters, digits, `-` and `_` are allowed"
+                );
+            }
+
+            if SOURCES.iter().any(|(builtin, _)| builtin == name) {
+                bail!("firmware source `{name}` is built in, pick another name");
+            }
+
+            source
+                .kind()
+                .with_context(|| format!("invalid firmware source `{name}`"))?;
+        }
+
+        if let Firmware::Named(..) = &self.firmware {
+            self.firmware.source(&self.firmware_sources)?;
+        }
+
+        Ok(())
+    }
+
+    /// `store/` of the project, or the shared store
+    pub fn store_path(&self) -> anyhow::Result<PathBuf> {
+        match self.shared_store {
+            true => shared_store_path(),
+            false => Ok(self.source_path.join("store")),
+        }
+    }
+
+    /// Sparse patterns of a store clone. None checks out everything, clones in the shared store
+    /// serve projects that map different paths.
+    pub fn sparse_patterns(&self, repo: &Repository) -> Option<Vec<BString>> {
+        (!self.shared_store).then(|| repo.mappings.sparse_patterns())
+    }
+
     pub async fn write(self) -> anyhow::Result<()> {
         debug!("writing to flip.toml @ {}", self.source_path.display());
 
@@ -49,3 +126,56 @@ impl Flip {
         .await?)
     }
 }
+
+/// `$XDG_CACHE_HOME/flippy/store`, or the platform's equivalent
+pub fn shared_store_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs::cache_dir()
+        .context("no cache directory for the shared store")?
+        .join("flippy")
+        .join("store"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_source(name: &str) -> Flip {
+        Flip {
+            firmware_sources: HashMap::from([(
+                name.to_string(),
+                FirmwareSource {
+                    directory: Some("http://127.0.0.1/directory.json".parse().unwrap()),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn named_source() {
+        let mut flip = with_source("lab");
+        flip.firmware = "lab@development".parse().unwrap();
+
+        assert!(flip.validate_firmware().is_ok());
+    }
+
+    #[test]
+    fn builtin_source_name() {
+        assert!(with_source("official").validate_firmware().is_err());
+    }
+
+    #[test]
+    fn invalid_source_name() {
+        assert!(with_source("my lab").validate_firmware().is_err());
+        assert!(with_source("lab@home").validate_firmware().is_err());
+    }
+
+    #[test]
+    fn firmware_from_missing_source() {
+        let mut flip = with_source("lab");
+        flip.firmware = "other@release".parse().unwrap();
+
+        assert!(flip.validate_firmware().is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Ownership manifest of every file and folder flippy has placed on the remote device. Anything
+//! not listed here belongs to the user (captures, dumps, ...etc) and is never removed.
+//!
+//! Stored as plain text, one absolute path per line, so it can be inspected on the SD card.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, bail};
+
+pub const MANAGED_FILE_PATH: &str = "/ext/.flippy_managed";
+const NOTICE: &str = "# FLIPPY MANAGED FILES: DO NOT MODIFY.";
+
+#[derive(Default, Debug)]
+pub struct ManagedFiles {
+    pub paths: BTreeSet<PathBuf>,
+}
+
+impl ManagedFiles {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = String::from(NOTICE);
+        buf.push('\n');
+
+        for path in &self.paths {
+            buf.push_str(&path.to_string_lossy());
+            buf.push('\n');
+        }
+
+        buf.into_bytes()
+    }
+
+    /// Returns Err if the notice mismatches or the data is not UTF-8.
+    pub fn deserialize(data: impl AsRef<[u8]>) -> Result<Self> {
+        let data = std::str::from_utf8(data.as_ref())?;
+        let mut lines = data.lines();
+
+        if lines.next() != Some(NOTICE) {
+            bail!("failed to deserialize managed files: notice mismatch");
+        }
+
+        Ok(Self {
+            paths: lines
+                .filter(|line| !line.is_empty())
+                .map(PathBuf::from)
+                .collect(),
+        })
+    }
+
+    pub fn owns(&self, path: impl AsRef<Path>) -> bool {
+        self.paths.contains(path.as_ref())
+    }
+
+    /// Records a path and every folder between it and `root`
+    pub fn insert(&mut self, root: impl AsRef<Path>, relative: impl AsRef<Path>) {
+        let root = root.as_ref();
+        let mut path = root.join(relative);
+
+        while path != root && self.paths.insert(path.clone()) {
+            if !path.pop() {
+                break;
+            }
+        }
+    }
+
+    /// Forgets a path and everything below it
+    pub fn remove(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+
+        let below = self
+            .paths
+            .range(path.to_path_buf()..)
+            .take_while(|p| p.starts_with(path))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for p in below {
+            self.paths.remove(&p);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::walking_diff::{
+        diff::{Op, Reason, diff},
+        tree::{RemoteNode, RemoteTree, Tree},
+    };
+
+    #[test]
+    fn round_trip() {
+        let mut managed = ManagedFiles::default();
+        managed.insert("/ext/subghz", "a/b.sub");
+        managed.insert("/ext/subghz", "c.sub");
+
+        let parsed = ManagedFiles::deserialize(managed.serialize()).unwrap();
+
+        assert_eq!(parsed.paths, managed.paths);
+        assert!(parsed.owns("/ext/subghz/a"));
+        assert!(parsed.owns("/ext/subghz/a/b.sub"));
+        assert!(!parsed.owns("/ext/subghz"));
+    }
+
+    #[test]
+    fn rejects_missing_notice() {
+        assert!(ManagedFiles::deserialize("/ext/subghz/a.sub\n").is_err());
+    }
+
+    #[test]
+    fn remove_forgets_everything_below() {
+        let mut managed = ManagedFiles::default();
+        managed.insert("/ext/subghz", "a/b.sub");
+        managed.insert("/ext/subghz", "ab.sub");
+
+        managed.remove("/ext/subghz/a");
+
+        assert_eq!(
+            managed.paths,
+            BTreeSet::from([PathBuf::from("/ext/subghz/ab.sub")])
+        );
+    }
+
+    #[test]
+    fn unmanaged_files_are_not_removed() {
+        let root = Path::new("/ext/subghz");
+
+        let mut managed = ManagedFiles::default();
+        managed.insert(root, "old.sub");
+        managed.insert(root, "dir/old.sub");
+
+        // Nothing is left locally, `capture.sub` and `dir/user.sub` were made on the device
+        let mut remote = RemoteTree::new();
+        remote.add_child_to(RemoteNode::new("old.sub", Some(1)), 0);
+        remote.add_child_to(RemoteNode::new("capture.sub", Some(1)), 0);
+        let dir = remote.add_child_to(RemoteNode::new("dir", None), 0);
+        remote.add_child_to(RemoteNode::new("old.sub", Some(1)), dir);
+        remote.add_child_to(RemoteNode::new("user.sub", Some(1)), dir);
+
+        let local = Tree::from_path_and_sizes::<PathBuf>(&[]);
+
+        let mut ops = vec![];
+        diff(
+            &local,
+            &remote,
+            &mut ops,
+            |_, _, _, _| Ok(false),
+            |relative| managed.owns(root.join(relative)),
+        )
+        .unwrap();
+
+        let mut removed = BTreeSet::new();
+        let mut unmanaged = BTreeSet::new();
+
+        for op in ops {
+            match op {
+                Op::Remove(path, Reason::RemoteOnly) => removed.insert(path),
+                Op::Unmanaged(path) => unmanaged.insert(path),
+                op => panic!("unexpected {op:?}"),
+            };
+        }
+
+        assert_eq!(
+            removed,
+            BTreeSet::from(["old.sub".into(), "dir/old.sub".into()])
+        );
+        assert_eq!(
+            unmanaged,
+            BTreeSet::from(["capture.sub".into(), "dir/user.sub".into()])
+        );
+    }
+}
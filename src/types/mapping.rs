@@ -1,6 +1,8 @@
 use gix::bstr::{BString, ByteVec};
 use serde::{Deserialize, Serialize};
 
+use super::remote_sync_file::MappingDigest;
+
 /// 3) One field per known mapping type
 #[derive(Debug, Deserialize, Serialize, Default)]
 #[serde(deny_unknown_fields)]
@@ -18,6 +20,10 @@ pub struct Mappings {
 pub struct MappingEntry {
     pub include: Vec<String>,
     pub exclude: Vec<String>,
+
+    /// Subdirectory of the database folder to upload into, overrides the repository's namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
 }
 
 impl MappingEntry {
@@ -46,43 +52,78 @@ pub enum Mapping<'a> {
 #[derive(Debug)]
 pub struct MappingInfo<'a> {
     pub patterns: &'a MappingEntry,
-    pub destination: &'static str,
+    pub destination: String,
     pub ignore: &'static [&'static str],
 }
 
-impl Mapping<'_> {
-    pub fn info(&self) -> MappingInfo<'_> {
+impl MappingInfo<'_> {
+    /// Fingerprint of everything that decides which files end up where. When it changes, the
+    /// files that were uploaded no longer match what a git diff would assume.
+    pub fn digest(&self) -> MappingDigest {
+        let mut ctx = md5::Context::new();
+
+        ctx.consume(&self.destination);
+        for (kind, patterns) in [
+            (b'i', &self.patterns.include),
+            (b'e', &self.patterns.exclude),
+        ] {
+            for pattern in patterns {
+                // Separators so ["ab"] and ["a", "b"] differ
+                ctx.consume([0, kind]);
+                ctx.consume(pattern);
+            }
+        }
+
+        ctx.finalize().0
+    }
+}
+
+impl<'a> Mapping<'a> {
+    /// Key of the mapping in `flip.toml`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mapping::SubGHz(_) => "subghz",
+            Mapping::Rfid(_) => "rfid",
+            Mapping::Nfc(_) => "nfc",
+            Mapping::IR(_) => "ir",
+            Mapping::IButton(_) => "ibutton",
+            Mapping::BadUSB(_) => "badusb",
+        }
+    }
+
+    pub fn entry(&self) -> &'a MappingEntry {
         match self {
-            Mapping::SubGHz(patterns) => MappingInfo {
-                patterns,
-                destination: "/ext/subghz",
-                ignore: &["assets"],
-            },
-            Mapping::Nfc(patterns) => MappingInfo {
-                patterns,
-                destination: "/ext/nfc",
-                ignore: &["assets", ".cache"],
-            },
-            Mapping::BadUSB(patterns) => MappingInfo {
-                patterns,
-                destination: "/ext/badusb",
-                ignore: &["assets", ".badusb.settings"],
-            },
-            Mapping::Rfid(patterns) => MappingInfo {
-                patterns,
-                destination: "/ext/lfrfid",
-                ignore: &[],
-            },
-            Mapping::IButton(patterns) => MappingInfo {
-                patterns,
-                destination: "/ext/ibutton",
-                ignore: &[],
-            },
-            Mapping::IR(patterns) => MappingInfo {
-                patterns,
-                destination: "/ext/infared",
-                ignore: &["assets"],
-            },
+            Mapping::SubGHz(entry)
+            | Mapping::Rfid(entry)
+            | Mapping::Nfc(entry)
+            | Mapping::IR(entry)
+            | Mapping::IButton(entry)
+            | Mapping::BadUSB(entry) => entry,
+        }
+    }
+
+    /// Where and how this mapping is uploaded. `namespace` is the repository's namespace, used
+    /// unless the mapping sets its own.
+    pub fn info(&self, namespace: Option<&str>) -> MappingInfo<'a> {
+        let (database, ignore): (_, &'static [&'static str]) = match self {
+            Mapping::SubGHz(_) => ("/ext/subghz", &["assets"]),
+            Mapping::Nfc(_) => ("/ext/nfc", &["assets", ".cache"]),
+            Mapping::BadUSB(_) => ("/ext/badusb", &["assets", ".badusb.settings"]),
+            Mapping::Rfid(_) => ("/ext/lfrfid", &[]),
+            Mapping::IButton(_) => ("/ext/ibutton", &[]),
+            Mapping::IR(_) => ("/ext/infared", &["assets"]),
+        };
+        let patterns = self.entry();
+
+        let destination = match patterns.namespace.as_deref().or(namespace) {
+            Some(namespace) => format!("{database}/{namespace}"),
+            None => database.to_string(),
+        };
+
+        MappingInfo {
+            patterns,
+            destination,
+            ignore,
         }
     }
 }
@@ -106,4 +147,18 @@ impl Mappings {
             .chain(nfc)
             .chain(ir)
     }
+
+    /// Every `include` of every mapping, what the store has to check out. Excludes only apply
+    /// to their own mapping, another one may still include the same files.
+    pub fn sparse_patterns(&self) -> Vec<BString> {
+        let mut patterns: Vec<BString> = self
+            .iter()
+            .flat_map(|mapping| mapping.entry().include.iter())
+            .map(|pattern| BString::from(pattern.as_str()))
+            .collect();
+
+        patterns.sort();
+        patterns.dedup();
+        patterns
+    }
 }
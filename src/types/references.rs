@@ -0,0 +1,74 @@
+//! Which projects use which entries of the shared store, see [`Flip::shared_store`]. Kept as
+//! `references.json` in the shared store itself.
+//!
+//! [`Flip::shared_store`]: super::flip::Flip::shared_store
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use uuid::Uuid;
+
+pub const REFERENCES_FILE: &str = "references.json";
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct References {
+    /// Keyed by the project directory
+    pub projects: BTreeMap<PathBuf, ProjectReferences>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ProjectReferences {
+    /// Clones and extracted archives, replaced on every `store fetch`
+    #[serde(default)]
+    pub repositories: BTreeSet<Uuid>,
+
+    /// Every firmware package `firmware update` downloaded
+    #[serde(default)]
+    pub firmware: BTreeSet<Uuid>,
+}
+
+impl References {
+    /// Empty when the shared store has no references yet
+    pub async fn load(store: &Path) -> Result<Self> {
+        let path = store.join(REFERENCES_FILE);
+
+        match fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn save(&self, store: &Path) -> Result<()> {
+        fs::create_dir_all(store).await?;
+        fs::write(
+            store.join(REFERENCES_FILE),
+            serde_json::to_vec_pretty(self)?,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub fn project(&mut self, project: &Path) -> &mut ProjectReferences {
+        self.projects.entry(project.to_path_buf()).or_default()
+    }
+
+    /// Forgets projects whose `flip.toml` is gone, they can no longer release their references
+    pub fn prune(&mut self) {
+        self.projects
+            .retain(|project, _| project.join("flip.toml").exists());
+    }
+
+    pub fn is_referenced(&self, uuid: &Uuid) -> bool {
+        self.projects
+            .values()
+            .any(|refs| refs.repositories.contains(uuid) || refs.firmware.contains(uuid))
+    }
+}
@@ -0,0 +1,347 @@
+//! A GitHub-style releases API as a firmware source, for forks that never publish a
+//! `directory.json`. Releases become versions of the `release` channel and prereleases versions
+//! of the `development` channel.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use gix::glob::wildmatch::Mode;
+use reqwest::header::{ACCEPT, LINK, USER_AGENT};
+use serde::Deserialize;
+use tracing::{debug, warn};
+use url::Url;
+
+use super::directory::{Channel, Directory, File, Id, Target, Version};
+
+#[derive(Deserialize, Debug)]
+struct Release {
+    tag_name: String,
+    body: Option<String>,
+
+    #[serde(default)]
+    prerelease: bool,
+
+    #[serde(default)]
+    draft: bool,
+    published_at: Option<jiff::Timestamp>,
+    created_at: Option<jiff::Timestamp>,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Asset {
+    name: String,
+    browser_download_url: Url,
+}
+
+/// Pages of the releases API read at most, older releases are not listed
+const MAX_PAGES: usize = 10;
+
+/// Every release of `url` with an asset matching `asset`, as a directory. Only the newest
+/// `MAX_PAGES` pages are read, 1000 releases on GitHub. `checksum` matches the
+/// asset holding the sha256 of the `.tgz`, it is only downloaded by [`fetch_checksums`].
+pub async fn fetch(url: &Url, asset: &str, checksum: Option<&str>) -> Result<Directory> {
+    let client = reqwest::Client::new();
+    let mut releases: Vec<Release> = vec![];
+
+    // 30 per page by default, 100 is the most GitHub allows
+    let mut page = url.clone();
+    if !page.query_pairs().any(|(key, _)| key == "per_page") {
+        page.query_pairs_mut().append_pair("per_page", "100");
+    }
+
+    for pages in 1..=MAX_PAGES {
+        // The GitHub API refuses requests without a user agent
+        let response = client
+            .get(page.clone())
+            .header(USER_AGENT, concat!("flippy/", env!("CARGO_PKG_VERSION")))
+            .header(ACCEPT, "application/vnd.github+json")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let next = response
+            .headers()
+            .get(LINK)
+            .and_then(|link| link.to_str().ok())
+            .and_then(next_page);
+
+        releases.extend(
+            response
+                .json::<Vec<Release>>()
+                .await
+                .with_context(|| format!("{url} is not a releases API"))?,
+        );
+
+        match next {
+            Some(_) if pages == MAX_PAGES => warn!(
+                "{url} has more than {MAX_PAGES} pages of releases, older ones are not listed"
+            ),
+            Some(next) => page = next,
+            None => break,
+        }
+    }
+
+    let mut stable = vec![];
+    let mut prereleases = vec![];
+
+    for release in releases.into_iter().filter(|release| !release.draft) {
+        let checksum = checksum
+            .and_then(|pattern| {
+                release
+                    .assets
+                    .iter()
+                    .find(|asset| matches(pattern, &asset.name))
+            })
+            .map(|asset| asset.browser_download_url.clone());
+
+        let files: Vec<File> = release
+            .assets
+            .iter()
+            .filter(|candidate| matches(asset, &candidate.name))
+            .map(|asset| File {
+                url: asset.browser_download_url.clone(),
+                target: target(&asset.name),
+                file_type: "update_tgz".to_string(),
+                sha256: None,
+                checksum: checksum.clone(),
+            })
+            .collect();
+
+        if files.is_empty() {
+            debug!(release.tag_name, "no asset matches `{asset}`, skipping");
+            continue;
+        }
+
+        let version = Version {
+            version: release.tag_name,
+            changelog: release.body.unwrap_or_default(),
+            timestamp: release
+                .published_at
+                .or(release.created_at)
+                .map_or(0, |time| time.as_second() as u64),
+            files,
+        };
+
+        match release.prerelease {
+            true => prereleases.push(version),
+            false => stable.push(version),
+        }
+    }
+
+    Ok(Directory {
+        channels: vec![
+            Channel {
+                id: Id::Release,
+                title: "Release".to_string(),
+                description: "Releases".to_string(),
+                versions: Some(stable),
+            },
+            Channel {
+                id: Id::Development,
+                title: "Development".to_string(),
+                description: "Prereleases".to_string(),
+                versions: Some(prereleases),
+            },
+        ],
+    })
+}
+
+/// Fills in the sha256 of files whose checksum is a separate asset, `sha256sum` output or only the
+/// hash
+pub async fn fetch_checksums(version: &mut Version) -> Result<()> {
+    let mut fetched: HashMap<Url, String> = HashMap::new();
+
+    for file in version
+        .files
+        .iter_mut()
+        .filter(|file| file.sha256.is_none())
+    {
+        let Some(url) = &file.checksum else {
+            continue;
+        };
+
+        if !fetched.contains_key(url) {
+            let text = reqwest::get(url.clone())
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            fetched.insert(url.clone(), text);
+        }
+
+        let name = file
+            .url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or_default();
+
+        file.sha256 = Some(
+            checksum_of(&fetched[url], name)
+                .with_context(|| format!("no sha256 of {name} in {url}"))?,
+        );
+    }
+
+    Ok(())
+}
+
+fn checksum_of(text: &str, file: &str) -> Option<String> {
+    text.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let hash = parts.next()?;
+
+        // `sha256sum` marks binary mode with a `*` before the name
+        let name = parts.next().map(|name| name.trim_start_matches('*'));
+
+        let is_hash = hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit());
+
+        (is_hash && name.is_none_or(|name| name == file)).then(|| hash.to_ascii_lowercase())
+    })
+}
+
+fn matches(pattern: &str, name: &str) -> bool {
+    gix::glob::wildmatch(pattern.into(), name.into(), Mode::IGNORE_CASE)
+}
+
+/// Forks name their packages like the official ones, `flipper-z-f7-update-<version>.tgz`. Only
+/// whole words count, commit hashes in the version can contain `f7` or `f18` too.
+fn target(name: &str) -> Target {
+    let name = name.to_ascii_lowercase();
+
+    let mut words = name.split(['-', '_', '.']);
+
+    match words.find(|word| matches!(*word, "f7" | "f18")) {
+        Some("f7") => Target::F7,
+        Some(_) => Target::F18,
+        None => Target::Any,
+    }
+}
+
+/// The `rel="next"` URL of a `Link` header
+fn next_page(link: &str) -> Option<Url> {
+    link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+
+        params
+            .split(';')
+            .any(|param| param.trim() == r#"rel="next""#)
+            .then(|| {
+                url.trim()
+                    .strip_prefix('<')?
+                    .strip_suffix('>')?
+                    .parse()
+                    .ok()
+            })
+            .flatten()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_from_asset_name() {
+        assert_eq!(target("flipper-z-f7-update-RM0101.tgz"), Target::F7);
+        assert_eq!(target("flipper-z-f18-update-RM0101.tgz"), Target::F18);
+        assert_eq!(target("Flipper_Z_F7_update.tgz"), Target::F7);
+        assert_eq!(target("update-RM0101.tgz"), Target::Any);
+    }
+
+    #[test]
+    fn target_ignores_commit_hashes() {
+        assert_eq!(target("flipper-z-f7-update-RM0101-2f18a3c.tgz"), Target::F7);
+        assert_eq!(target("flipper-z-f7-update-6df7e8d.tgz"), Target::F7);
+        assert_eq!(target("update-RM0101-af18f7e.tgz"), Target::Any);
+    }
+
+    const F7: &str = "5484b40999c47003346d53c3a354a67c743f4ff0eacdbf31ddcc2867141b2f7e";
+    const F18: &str = "9566d5296fa718d824605403afec2b7e2edb50b8848cc24300a8d31bcebb7aad";
+
+    #[test]
+    fn checksum_from_sha256sum_output() {
+        let text = format!(
+            "{F18}  flipper-z-f18-update-RM0101.tgz\n{F7} *flipper-z-f7-update-RM0101.tgz\n"
+        );
+
+        assert_eq!(
+            checksum_of(&text, "flipper-z-f7-update-RM0101.tgz").as_deref(),
+            Some(F7)
+        );
+        assert_eq!(
+            checksum_of(&text, "flipper-z-f18-update-RM0101.tgz").as_deref(),
+            Some(F18)
+        );
+        assert_eq!(checksum_of(&text, "other.tgz"), None);
+    }
+
+    #[test]
+    fn checksum_from_hash_only() {
+        let text = format!("{}\n", F7.to_ascii_uppercase());
+
+        assert_eq!(checksum_of(&text, "any.tgz").as_deref(), Some(F7));
+        assert_eq!(checksum_of("not a hash\n", "any.tgz"), None);
+    }
+
+    #[test]
+    fn next_page_from_link_header() {
+        let link = r#"<https://api.github.com/repositories/1/releases?per_page=100&page=2>; rel="next", <https://api.github.com/repositories/1/releases?per_page=100&page=5>; rel="last""#;
+
+        assert_eq!(
+            next_page(link).unwrap().as_str(),
+            "https://api.github.com/repositories/1/releases?per_page=100&page=2"
+        );
+    }
+
+    #[test]
+    fn no_next_page_on_the_last_one() {
+        let link = r#"<https://api.github.com/repositories/1/releases?per_page=100&page=4>; rel="prev", <https://api.github.com/repositories/1/releases?per_page=100&page=1>; rel="first""#;
+
+        assert!(next_page(link).is_none());
+    }
+
+    #[tokio::test]
+    async fn fetch_follows_next_page() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            for (tag, link) in [
+                (
+                    "1.1.0",
+                    format!("<http://{address}/releases?page=2>; rel=\"next\""),
+                ),
+                (
+                    "1.0.0",
+                    format!("<http://{address}/releases?page=1>; rel=\"first\""),
+                ),
+            ] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+
+                let body = format!(
+                    r#"[{{"tag_name":"{tag}","assets":[{{"name":"fw-{tag}.tgz","browser_download_url":"http://{address}/fw-{tag}.tgz"}}]}}]"#
+                );
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\nlink: {link}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let url = format!("http://{address}/releases").parse().unwrap();
+        let directory = fetch(&url, "*.tgz", None).await.unwrap();
+
+        let versions = directory.channels[0]
+            .versions
+            .iter()
+            .flatten()
+            .map(|version| version.version.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(versions, ["1.1.0", "1.0.0"]);
+    }
+}
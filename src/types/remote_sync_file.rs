@@ -6,36 +6,61 @@ use anyhow::{Result, bail};
 use uuid::Uuid;
 
 pub const SYNC_FILE_PATH: &str = "/ext/.flippy_do_not_remove";
-const VERSION: u8 = 1;
+const VERSION: u8 = 2;
 const NOTICE: &[u8] = b"FLIPPY SYNC FILE: DO NOT MODIFY.";
 const NOTICE_LENGTH: usize = NOTICE.len();
 
-// Header layout: u8 + [u8; NOTICE_LENGTH], packed to avoid padding
-//#[repr(C, packed)]
-//struct HeaderRaw {
-//    version: u8,
-//    notice: [u8; NOTICE_LENGTH],
-//}
+const UUID_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 20;
+const DIGEST_LENGTH: usize = 16;
 
-/// Repo layout: 16-byte UUID + 20-byte hash, packed
-#[repr(C, packed)]
+/// Digest of a mapping's patterns and destination, see [`crate::types::mapping::MappingInfo::digest`]
+pub type MappingDigest = [u8; DIGEST_LENGTH];
+
+/// v1 layout: 16-byte UUID + 20-byte hash
+/// v2 layout: 16-byte UUID + 20-byte hash + u8 mapping count + 16-byte digest per mapping
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Repo {
-    pub uuid: [u8; 16],
-    pub hash: [u8; 20],
+    pub uuid: [u8; UUID_LENGTH],
+    pub hash: [u8; HASH_LENGTH],
+
+    /// Digests of every mapping uploaded at `hash`. None when migrated from a v1 sync file, where
+    /// the mappings were never recorded.
+    pub mappings: Option<Vec<MappingDigest>>,
 }
 
+impl Repo {
+    /// Whether the mapping was uploaded with the exact same patterns and destination, meaning a
+    /// git diff from `hash` is enough to bring it up to date.
+    pub fn tracks(&self, digest: &MappingDigest) -> bool {
+        match &self.mappings {
+            Some(mappings) => mappings.contains(digest),
+            // v1 never recorded them, files newly included since could be missing
+            None => false,
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct SyncFile {
     pub repositories: Vec<Repo>,
 }
 
 impl SyncFile {
-    /// Serializes the SyncFile into a byte vector.
-    /// NOTE: When there is only one repository, the length of the output is 69...
-    /// NOTE: Nice!
+    /// Serializes the SyncFile into a byte vector, always in the latest version.
     pub fn serialize(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(
-            1 + NOTICE_LENGTH + self.repositories.len() * std::mem::size_of::<Repo>(),
+            1 + NOTICE_LENGTH
+                + self
+                    .repositories
+                    .iter()
+                    .map(|repo| {
+                        UUID_LENGTH
+                            + HASH_LENGTH
+                            + 1
+                            + repo.mappings.as_ref().map_or(0, Vec::len) * DIGEST_LENGTH
+                    })
+                    .sum::<usize>(),
         );
         // version
         buf.push(VERSION);
@@ -47,12 +72,20 @@ impl SyncFile {
         for repo in &self.repositories {
             buf.extend_from_slice(&repo.uuid);
             buf.extend_from_slice(&repo.hash);
+
+            let mappings = repo.mappings.as_deref().unwrap_or_default();
+
+            // There are only six database types
+            buf.push(mappings.len() as u8);
+            for digest in mappings {
+                buf.extend_from_slice(digest);
+            }
         }
 
         buf
     }
 
-    /// Deserializes a byte slice into a SyncFile.
+    /// Deserializes a byte slice into a SyncFile. v1 files are migrated to v2.
     ///
     /// Returns Err if version or notice mismatches, or if length is invalid.
     pub fn deserialize(data: impl AsRef<[u8]>) -> Result<Self> {
@@ -65,12 +98,6 @@ impl SyncFile {
 
         // parse header
         let version = data[0];
-        if version != VERSION {
-            bail!(
-                "failed to deserialize sync file: unsupported version {}",
-                version
-            );
-        }
 
         let notice_bytes = &data[1..1 + NOTICE_LENGTH];
 
@@ -80,10 +107,24 @@ impl SyncFile {
             );
         }
 
-        // the rest must be an exact multiple of Repo size
         let repo_data = &data[1 + NOTICE_LENGTH..];
-        let repo_size = std::mem::size_of::<Repo>();
-        if repo_data.len() % repo_size != 0 {
+
+        let repositories = match version {
+            1 => Self::deserialize_v1(repo_data)?,
+            2 => Self::deserialize_v2(repo_data)?,
+            _ => bail!(
+                "failed to deserialize sync file: unsupported version {}",
+                version
+            ),
+        };
+
+        Ok(SyncFile { repositories })
+    }
+
+    fn deserialize_v1(repo_data: &[u8]) -> Result<Vec<Repo>> {
+        // the rest must be an exact multiple of Repo size
+        let repo_size = UUID_LENGTH + HASH_LENGTH;
+        if !repo_data.len().is_multiple_of(repo_size) {
             bail!(
                 "failed to deserialize sync file: repository data length {} is not a multiple of {}",
                 repo_data.len(),
@@ -91,31 +132,145 @@ impl SyncFile {
             );
         }
 
-        let count = repo_data.len() / repo_size;
-        let mut repositories = Vec::with_capacity(count);
+        Ok(repo_data
+            .chunks_exact(repo_size)
+            .map(|chunk| {
+                let (uuid, hash) = chunk.split_at(UUID_LENGTH);
+
+                Repo {
+                    // safe because chunk has exactly the right length
+                    uuid: uuid.try_into().unwrap(),
+                    hash: hash.try_into().unwrap(),
+                    mappings: None,
+                }
+            })
+            .collect())
+    }
+
+    fn deserialize_v2(mut repo_data: &[u8]) -> Result<Vec<Repo>> {
+        let mut repositories = Vec::new();
+
+        while !repo_data.is_empty() {
+            let header_size = UUID_LENGTH + HASH_LENGTH + 1;
+            if repo_data.len() < header_size {
+                bail!("failed to deserialize sync file: truncated repository entry");
+            }
+
+            let (header, rest) = repo_data.split_at(header_size);
+            let count = header[UUID_LENGTH + HASH_LENGTH] as usize;
+
+            if rest.len() < count * DIGEST_LENGTH {
+                bail!(
+                    "failed to deserialize sync file: expected {count} mapping digests, data too short"
+                );
+            }
 
-        for i in 0..count {
-            let start = i * repo_size;
-            let chunk = &repo_data[start..start + repo_size];
+            let (digests, rest) = rest.split_at(count * DIGEST_LENGTH);
 
-            // safe because chunk has exactly the right length
-            let mut uuid = [0u8; 16];
-            let mut hash = [0u8; 20];
-            uuid.copy_from_slice(&chunk[0..16]);
-            hash.copy_from_slice(&chunk[16..36]);
+            repositories.push(Repo {
+                uuid: header[..UUID_LENGTH].try_into()?,
+                hash: header[UUID_LENGTH..UUID_LENGTH + HASH_LENGTH].try_into()?,
+                mappings: Some(
+                    digests
+                        .chunks_exact(DIGEST_LENGTH)
+                        .map(|digest| digest.try_into())
+                        .collect::<Result<_, _>>()?,
+                ),
+            });
 
-            repositories.push(Repo { uuid, hash });
+            repo_data = rest;
         }
 
-        Ok(SyncFile { repositories })
+        Ok(repositories)
     }
 
-    pub fn find_hash(&self, uuid: &Uuid) -> Option<&[u8; 20]> {
+    pub fn find(&self, uuid: &Uuid) -> Option<&Repo> {
         let bytes = uuid.as_bytes();
 
-        self.repositories
-            .iter()
-            .find(|repo| repo.uuid == *bytes)
-            .map(|x| &x.hash)
+        self.repositories.iter().find(|repo| repo.uuid == *bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo(byte: u8, mappings: Option<Vec<MappingDigest>>) -> Repo {
+        Repo {
+            uuid: [byte; UUID_LENGTH],
+            hash: [byte + 1; HASH_LENGTH],
+            mappings,
+        }
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let file = SyncFile {
+            repositories: vec![
+                repo(1, Some(vec![[7; DIGEST_LENGTH], [8; DIGEST_LENGTH]])),
+                repo(3, Some(vec![])),
+            ],
+        };
+
+        let data = file.serialize();
+        assert_eq!(data[0], VERSION);
+
+        let parsed = SyncFile::deserialize(&data).unwrap();
+        assert_eq!(parsed.repositories, file.repositories);
+    }
+
+    #[test]
+    fn v1_migrates_to_v2() {
+        let mut data = vec![1];
+        data.extend_from_slice(NOTICE);
+        for byte in [1, 3] {
+            data.extend_from_slice(&[byte; UUID_LENGTH]);
+            data.extend_from_slice(&[byte + 1; HASH_LENGTH]);
+        }
+
+        let parsed = SyncFile::deserialize(&data).unwrap();
+        assert_eq!(parsed.repositories, vec![repo(1, None), repo(3, None)]);
+
+        // Unknown mappings are walked once, the next upload records them
+        assert!(!parsed.repositories[0].tracks(&[0; DIGEST_LENGTH]));
+
+        let migrated = SyncFile::deserialize(parsed.serialize()).unwrap();
+        assert_eq!(
+            migrated.repositories,
+            vec![repo(1, Some(vec![])), repo(3, Some(vec![]))]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let data = SyncFile {
+            repositories: vec![repo(1, Some(vec![[7; DIGEST_LENGTH]]))],
+        }
+        .serialize();
+
+        // Inside the notice, the repository header and the digests
+        for len in [NOTICE_LENGTH, 1 + NOTICE_LENGTH + 10, data.len() - 1] {
+            assert!(SyncFile::deserialize(&data[..len]).is_err(), "{len}");
+        }
+
+        let mut v1 = vec![1];
+        v1.extend_from_slice(NOTICE);
+        v1.extend_from_slice(&[1; UUID_LENGTH + HASH_LENGTH - 1]);
+        assert!(SyncFile::deserialize(&v1).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_version_and_notice() {
+        let mut data = SyncFile::default().serialize();
+
+        data[0] = 3;
+        assert!(SyncFile::deserialize(&data).is_err());
+
+        data[0] = 0;
+        assert!(SyncFile::deserialize(&data).is_err());
+
+        data[0] = VERSION;
+        data[1] ^= 0xff;
+        assert!(SyncFile::deserialize(&data).is_err());
     }
 }
@@ -1,11 +1,269 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, bail};
+use gix::url::Scheme;
 use serde::{Deserialize, Serialize};
+use url::Url;
 use uuid::Uuid;
 
-use super::mapping::Mappings;
+use super::{flip::Flip, mapping::Mappings};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Repository {
-    pub url: String,
+    /// git repository, cloned into the store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    /// Folder on this machine, read in place. Relative to the project.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+
+    /// `.zip`, `.tar.gz` or `.tgz` download, extracted into the store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<Url>,
+
+    /// Checksum of `archive`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
     pub uuid: Uuid,
+
+    /// Subdirectory of every database folder to upload into, e.g. `/ext/subghz/<namespace>`.
+    /// Lets repositories that map the same database type coexist.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+
+    /// Follow this branch instead of the remote's default branch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+
+    /// Stay on this tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+
+    /// Stay on this commit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+
+    /// Credentials for private HTTPS repositories
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<Auth>,
+
     pub mappings: Mappings,
 }
+
+/// Where the credentials of a private repository come from. Secrets never go into `flip.toml`,
+/// only the environment variable or helper that provides them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    /// Defaults to the user in the URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// Environment variable holding the password or access token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_env: Option<String>,
+
+    /// git credential helper, like `credential.helper`: `store`, `/path/to/helper` or
+    /// `!shell command`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub helper: Option<String>,
+}
+
+/// Where the files of a repository come from, see [`Repository::source`]
+#[derive(Debug, Clone, Copy)]
+pub enum Source<'a> {
+    Git(&'a str),
+    Local(&'a Path),
+    Archive { url: &'a Url, sha256: &'a str },
+}
+
+/// What the store clone of a repository is checked out at, see [`Repository::pin`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pin {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+impl Display for Pin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pin::Branch(branch) => write!(f, "branch `{branch}`"),
+            Pin::Tag(tag) => write!(f, "tag `{tag}`"),
+            Pin::Rev(rev) => write!(f, "commit `{rev}`"),
+        }
+    }
+}
+
+impl Repository {
+    /// Returns Err if not exactly one of `url`, `path` or `archive` is set, or `sha256` is set
+    /// without `archive`.
+    pub fn source(&self) -> Result<Source<'_>> {
+        match (&self.url, &self.path, &self.archive, &self.sha256) {
+            (Some(url), None, None, None) => Ok(Source::Git(url)),
+            (None, Some(path), None, None) => Ok(Source::Local(path)),
+            (None, None, Some(url), Some(sha256)) => Ok(Source::Archive { url, sha256 }),
+            (None, None, Some(_), None) => bail!("`archive` needs its `sha256`"),
+            (_, _, None, Some(_)) => bail!("`sha256` only applies to an `archive`"),
+            _ => bail!("exactly one of `url`, `path` or `archive` must be set"),
+        }
+    }
+
+    /// Where the files are read from, the store for everything but local folders
+    pub fn local_path(&self, flip: &Flip) -> Result<PathBuf> {
+        Ok(match self.source()? {
+            Source::Local(path) => flip.source_path.join(path),
+            Source::Git(_) | Source::Archive { .. } => {
+                flip.store_path()?.join(self.uuid.to_string())
+            }
+        })
+    }
+
+    /// Returns Err if git-only settings are set on another source, or the checksum is malformed.
+    pub fn validate_source(&self) -> Result<()> {
+        match self.source()? {
+            Source::Git(_) => {}
+            Source::Archive { url, sha256 } => {
+                if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    bail!("`sha256` must be 64 hexadecimal characters");
+                }
+
+                crate::source::ArchiveKind::from_url(url)?;
+            }
+            Source::Local(_) => {}
+        }
+
+        if self.url.is_none() && (self.pin()?.is_some() || self.auth.is_some()) {
+            bail!("`branch`, `tag`, `rev` and `auth` only apply to git repositories");
+        }
+
+        Ok(())
+    }
+
+    /// None when the repository follows the remote's default branch.
+    ///
+    /// Returns Err if more than one of `branch`, `tag` and `rev` is set.
+    pub fn pin(&self) -> Result<Option<Pin>> {
+        match (&self.branch, &self.tag, &self.rev) {
+            (None, None, None) => Ok(None),
+            (Some(branch), None, None) => Ok(Some(Pin::Branch(branch.clone()))),
+            (None, Some(tag), None) => Ok(Some(Pin::Tag(tag.clone()))),
+            (None, None, Some(rev)) => Ok(Some(Pin::Rev(rev.clone()))),
+            _ => bail!("only one of `branch`, `tag` or `rev` may be set"),
+        }
+    }
+
+    /// Returns Err if `auth` does not name exactly one source or is set for a non-HTTPS remote.
+    pub fn validate_auth(&self) -> Result<()> {
+        let Some(auth) = &self.auth else {
+            return Ok(());
+        };
+
+        match (&auth.password_env, &auth.helper) {
+            (Some(_), Some(_)) => bail!("only one of `password_env` or `helper` may be set"),
+            (None, None) => bail!("one of `password_env` or `helper` must be set"),
+            _ => {}
+        }
+
+        let Some(url) = &self.url else {
+            return Ok(());
+        };
+
+        let url = gix::url::parse(url.as_str().into())?;
+
+        match url.scheme {
+            Scheme::Https => {}
+            // A git server on this machine, nothing leaves it
+            Scheme::Http if is_loopback(url.host()) => {}
+            Scheme::Http => bail!(
+                "credentials are never sent over plain http, use https (http only works for localhost)"
+            ),
+            scheme => {
+                bail!("`auth` only applies to https remotes, {scheme} authenticates on its own")
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_pin(&mut self, pin: Option<Pin>) {
+        self.branch = None;
+        self.tag = None;
+        self.rev = None;
+
+        match pin {
+            Some(Pin::Branch(branch)) => self.branch = Some(branch),
+            Some(Pin::Tag(tag)) => self.tag = Some(tag),
+            Some(Pin::Rev(rev)) => self.rev = Some(rev),
+            None => {}
+        }
+    }
+}
+
+fn is_loopback(host: Option<&str>) -> bool {
+    match host {
+        Some("localhost") => true,
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_auth(url: &str) -> Repository {
+        Repository {
+            url: Some(url.to_string()),
+            auth: Some(Auth {
+                username: None,
+                password_env: Some("TOKEN".to_string()),
+                helper: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn auth_over_https() {
+        assert!(
+            with_auth("https://example.com/db.git")
+                .validate_auth()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn auth_refused_over_remote_http() {
+        assert!(
+            with_auth("http://example.com/db.git")
+                .validate_auth()
+                .is_err()
+        );
+        assert!(
+            with_auth("http://192.168.1.2/db.git")
+                .validate_auth()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn auth_allowed_over_loopback_http() {
+        for url in [
+            "http://localhost:8080/db.git",
+            "http://127.0.0.1/db.git",
+            "http://[::1]:3000/db.git",
+        ] {
+            assert!(with_auth(url).validate_auth().is_ok(), "{url}");
+        }
+    }
+}
@@ -51,3 +51,18 @@ pub fn validate_project_name(input: &str) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Namespaces become a single directory on the flipper, under a database folder.
+pub fn validate_namespace(input: &str) -> anyhow::Result<()> {
+    if input.is_empty() || input == "." || input == ".." {
+        return Err(anyhow!("Namespace `{input}` is not a valid directory name"));
+    }
+
+    if input.contains(['/', '\\']) {
+        return Err(anyhow!(
+            "Namespace `{input}` must be a single directory, not a path"
+        ));
+    }
+
+    Ok(())
+}
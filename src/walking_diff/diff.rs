@@ -1,6 +1,7 @@
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 
 use crate::walking_diff::tree::{RemoteTree, Tree};
 
@@ -9,32 +10,105 @@ pub enum Op {
     /// Root dir
     Repo(PathBuf),
     /// src -> destination
-    Mapping(String, &'static str),
-    Copy(PathBuf),
-    CreateDir(PathBuf),
-    Remove(PathBuf),
+    Mapping(String, String),
+    Copy(PathBuf, Reason),
+    CreateDir(PathBuf, Reason),
+    Remove(PathBuf, Reason),
+    /// from -> to, both relative to the same mapping root
+    Rename(PathBuf, PathBuf, Reason),
+    /// Remote-only, but not placed by flippy, so it is left alone
+    Unmanaged(PathBuf),
+}
+
+/// Why an operation was emitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    /// Changed between the commit in the sync file and HEAD
+    GitDiff,
+    /// Only exists locally
+    Missing,
+    /// Exists on both, but the size or MD5 differs
+    Mismatch,
+    /// Only exists on the remote, and flippy owns it
+    RemoteOnly,
+    /// Folder with nothing left inside after a git diff, only removed when it really is empty
+    Emptied,
 }
 
 pub trait DiffFn: FnMut(&Path, Option<u32>, usize, usize) -> Result<bool> {}
 impl<T> DiffFn for T where T: FnMut(&Path, Option<u32>, usize, usize) -> Result<bool> {}
 
+/// Whether flippy placed the remote file or folder at a path, and may remove it.
+pub trait OwnedFn: Fn(&Path) -> bool {}
+impl<T> OwnedFn for T where T: Fn(&Path) -> bool {}
+
 pub fn diff(
     local: &Tree,
     remote: &RemoteTree,
     ops: &mut Vec<Op>,
     different: impl DiffFn,
+    owned: impl OwnedFn,
 ) -> Result<()> {
-    let matches = prune_pass(local, remote, ops)?;
+    let matches = prune_pass(local, remote, ops, &owned)?;
     creation_pass(local, remote, ops)?;
     update_pass(local, matches, different, ops)?;
 
     Ok(())
 }
 
+/// Emits removals for a remote-only subtree, only touching what flippy owns. Returns whether the
+/// whole subtree could be removed.
+fn emit_remove_subtree(
+    remote: &RemoteTree,
+    remote_idx: usize,
+    path: &Path,
+    ops: &mut Vec<Op>,
+    owned: &impl OwnedFn,
+) -> Result<bool> {
+    let remote_node = &remote.nodes[remote_idx];
+    let relative = path.strip_prefix("/")?.to_path_buf();
+
+    if !owned(&relative) {
+        ops.push(Op::Unmanaged(relative));
+        return Ok(false);
+    }
+
+    // Files, and folders where everything inside is owned, are removed in one go
+    if remote_node.size.is_some() || subtree_owned(remote, remote_idx, path, owned) {
+        ops.push(Op::Remove(relative, Reason::RemoteOnly));
+        return Ok(true);
+    }
+
+    for (name, &child_idx) in &remote_node.children {
+        emit_remove_subtree(remote, child_idx, &path.join(name.as_ref()), ops, owned)?;
+    }
+
+    Ok(false)
+}
+
+fn subtree_owned(
+    remote: &RemoteTree,
+    remote_idx: usize,
+    path: &Path,
+    owned: &impl OwnedFn,
+) -> bool {
+    remote.nodes[remote_idx]
+        .children
+        .iter()
+        .all(|(name, &child_idx)| {
+            let child_path = path.join(name.as_ref());
+
+            child_path.strip_prefix("/").is_ok_and(owned)
+                && subtree_owned(remote, child_idx, &child_path, owned)
+        })
+}
+
 fn prune_pass(
     local: &Tree,
     remote: &RemoteTree,
     ops: &mut Vec<Op>,
+    owned: &impl OwnedFn,
 ) -> Result<Vec<(PathBuf, usize, usize, usize)>> {
     let mut matched = Vec::new();
 
@@ -48,6 +122,7 @@ fn prune_pass(
         path: &Path,
         ops: &mut Vec<Op>,
         matched: &mut Vec<(PathBuf, usize, usize, usize)>,
+        owned: &impl OwnedFn,
     ) -> Result<()> {
         // Record this node as unchanged
         matched.push((path.to_path_buf(), local_idx, remote_idx, remote_parent));
@@ -68,10 +143,11 @@ fn prune_pass(
                     &child_path,
                     ops,
                     matched,
+                    owned,
                 )?;
             } else {
                 // Remote-only: remove subtree
-                ops.push(Op::Remove(child_path.strip_prefix("/")?.to_path_buf()));
+                emit_remove_subtree(remote, r_child_idx, &child_path, ops, owned)?;
             }
         }
 
@@ -79,7 +155,7 @@ fn prune_pass(
     }
 
     let root = PathBuf::from("/");
-    walk(local, remote, 0, 0, 0, &root, ops, &mut matched)?;
+    walk(local, remote, 0, 0, 0, &root, ops, &mut matched, owned)?;
     Ok(matched)
 }
 
@@ -93,11 +169,17 @@ fn emit_create_subtree(
     let local_node = &tree.nodes[local_idx];
     if local_node.children.is_empty() {
         // File
-        ops.push(Op::Copy(path.strip_prefix("/")?.to_path_buf()));
+        ops.push(Op::Copy(
+            path.strip_prefix("/")?.to_path_buf(),
+            Reason::Missing,
+        ));
     } else {
         // Directory
         if path != &PathBuf::from("/") {
-            ops.push(Op::CreateDir(path.strip_prefix("/")?.to_path_buf()));
+            ops.push(Op::CreateDir(
+                path.strip_prefix("/")?.to_path_buf(),
+                Reason::Missing,
+            ));
         }
         for (name, &child_idx) in &local_node.children {
             let mut child_path = path.clone();
@@ -163,7 +245,10 @@ fn update_pass(
     for (path, l_idx, r_idx, r_parent) in matched {
         let local_node = &local.nodes[l_idx];
         if local_node.children.is_empty() && different(&path, local_node.size, r_idx, r_parent)? {
-            ops.push(Op::Copy(path.strip_prefix("/")?.to_path_buf()));
+            ops.push(Op::Copy(
+                path.strip_prefix("/")?.to_path_buf(),
+                Reason::Mismatch,
+            ));
         }
     }
 
@@ -3,7 +3,7 @@
 //! practically O(1) for time, processing about ~7000 paths in 1ms
 
 use anyhow::Result;
-use flipper_rpc::{fs::FsReadDir, transport::serial::rpc::SerialRpcTransport};
+use flipper_rpc::{error::Error, fs::FsReadDir, rpc};
 use fxhash::{FxBuildHasher, FxHashMap};
 use hyperloglockless::HyperLogLog;
 use std::{
@@ -157,9 +157,9 @@ impl RemoteTree {
     // WARNING: THIS IS HIGHLY INNEFICIENT. It re-allocates data! :scared:
     // Do not use this in prod unless you are a goober
     pub fn from_remote(
-        cli: &mut SerialRpcTransport,
+        cli: &mut impl FsReadDir,
         root: impl AsRef<Path>,
-        ignore: &'static [&'static str],
+        ignore: &[&str],
     ) -> Result<Self> {
         let mut tree = Self::new();
 
@@ -168,7 +168,14 @@ impl RemoteTree {
         queue.push_back((0usize, root.as_ref().to_path_buf()));
 
         while let Some((parent_idx, path)) = queue.pop_front() {
-            let items = cli.fs_read_dir(&path, false)?;
+            let items = match cli.fs_read_dir(&path, false) {
+                Ok(items) => items,
+                // The root has not been created yet, so everything is local-only
+                Err(Error::Rpc(rpc::error::Error::StorageError(
+                    rpc::error::StorageError::NotFound,
+                ))) if parent_idx == 0 => break,
+                Err(e) => return Err(e.into()),
+            };
             for item in items {
                 match item {
                     flipper_rpc::rpc::res::ReadDirItem::File(name, size, _hash) => {